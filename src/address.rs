@@ -0,0 +1,243 @@
+use crate::{context, lock};
+use crate::error::{CResult, copy_c_string, report};
+use crate::socket::CAddressHandle;
+
+use std::collections::BTreeMap;
+use std::ffi::{CStr, c_char};
+use std::net::SocketAddr;
+
+pub const INVALID_ADDRESS: CAddressHandle = 0;
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Endpoint {
+    Udp(SocketAddr),
+    // Endpoint IDs of other transports, only compared byte for byte
+    Opaque(Vec<u8>)
+}
+
+// Gives every endpoint one address handle, so the same peer always maps to the same handle
+pub(crate) struct AddressRegistry {
+    next_handle: CAddressHandle,
+    endpoints: BTreeMap<CAddressHandle, Endpoint>,
+    handles: BTreeMap<Endpoint, CAddressHandle>
+}
+impl AddressRegistry {
+    pub(crate) const fn new() -> Self {
+        Self {
+            next_handle: INVALID_ADDRESS + 1,
+            endpoints: BTreeMap::new(),
+            handles: BTreeMap::new()
+        }
+    }
+
+    pub(crate) fn register(&mut self, endpoint: Endpoint) -> CAddressHandle {
+        if let Some(handle) = self.handles.get(&endpoint) {
+            return *handle;
+        }
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.endpoints.insert(handle, endpoint.clone());
+        self.handles.insert(endpoint, handle);
+        handle
+    }
+
+    pub(crate) fn handle(&self, endpoint: &Endpoint) -> Option<CAddressHandle> {
+        self.handles.get(endpoint).copied()
+    }
+
+    pub(crate) fn endpoint(&self, handle: CAddressHandle) -> Option<&Endpoint> {
+        self.endpoints.get(&handle)
+    }
+
+    fn unregister(&mut self, handle: CAddressHandle) -> bool {
+        match self.endpoints.remove(&handle) {
+            Some(endpoint) => {
+                self.handles.remove(&endpoint);
+                true
+            }
+            None => false
+        }
+    }
+}
+
+fn invalid_address(handle: CAddressHandle) -> CResult {
+    report(CResult::InvalidAddress, format!("Address handle {} is not registered.", handle))
+}
+
+/// # Safety
+/// `addr` must be null or a valid nul-terminated string.
+///
+/// Returns the address handle of an "ip:port" endpoint, registering it if needed. IPv6 addresses are written as "[ip]:port".
+/// Returns INVALID_ADDRESS if addr can not be parsed.
+#[no_mangle]
+pub unsafe extern fn ggrs_address_register(addr: *const c_char) -> CAddressHandle {
+    let parsed = if addr.is_null() {
+        None
+    } else {
+        unsafe { CStr::from_ptr(addr) }.to_str().ok().and_then(|s| s.parse::<SocketAddr>().ok())
+    };
+    match parsed {
+        Some(socket_addr) => lock(&context().addresses).register(Endpoint::Udp(socket_addr)),
+        None => {
+            report(CResult::InvalidAddress, "Unable to parse address, expected ip:port.");
+            INVALID_ADDRESS
+        }
+    }
+}
+
+/// # Safety
+/// `id` must point to at least `id_length` readable bytes.
+///
+/// Returns the address handle of an opaque endpoint ID, registering it if needed.
+#[no_mangle]
+pub unsafe extern fn ggrs_address_register_bytes(id: *const u8, id_length: usize) -> CAddressHandle {
+    if id.is_null() || id_length == 0 {
+        report(CResult::InvalidAddress, "Endpoint IDs can not be empty.");
+        return INVALID_ADDRESS;
+    }
+    let id = unsafe { std::slice::from_raw_parts(id, id_length) }.to_vec();
+    lock(&context().addresses).register(Endpoint::Opaque(id))
+}
+
+#[no_mangle]
+pub extern fn ggrs_address_unregister(handle: CAddressHandle) -> CResult {
+    if lock(&context().addresses).unregister(handle) {
+        CResult::Ok
+    } else {
+        invalid_address(handle)
+    }
+}
+
+/// # Safety
+/// `buffer` must be null or point to at least `buffer_length` writable bytes.
+///
+/// Copies the nul-terminated "ip:port" of handle into buffer, truncating it if needed. Opaque IDs are written as hex.
+/// Returns the length of the full string without the terminator, or 0 if handle is not registered.
+#[no_mangle]
+pub unsafe extern fn ggrs_address_to_string(handle: CAddressHandle, buffer: *mut c_char, buffer_length: usize) -> usize {
+    let text = match lock(&context().addresses).endpoint(handle) {
+        Some(Endpoint::Udp(socket_addr)) => socket_addr.to_string(),
+        Some(Endpoint::Opaque(id)) => id.iter().map(|b| format!("{:02x}", b)).collect(),
+        None => {
+            invalid_address(handle);
+            return 0;
+        }
+    };
+    unsafe { copy_c_string(text.as_bytes(), buffer, buffer_length) }
+}
+
+/// # Safety
+/// `buffer` must be null or point to at least `buffer_length` writable bytes.
+///
+/// Returns the length of the opaque endpoint ID of handle, the ID is only copied if buffer_length is large enough.
+/// Returns 0 if handle is not registered or is an "ip:port" endpoint.
+#[no_mangle]
+pub unsafe extern fn ggrs_address_bytes(handle: CAddressHandle, buffer: *mut u8, buffer_length: usize) -> usize {
+    match lock(&context().addresses).endpoint(handle) {
+        Some(Endpoint::Opaque(id)) => {
+            if !buffer.is_null() && buffer_length >= id.len() {
+                unsafe { std::ptr::copy_nonoverlapping(id.as_ptr(), buffer, id.len()) };
+            }
+            id.len()
+        }
+        Some(Endpoint::Udp(_)) => {
+            report(CResult::InvalidRequest, format!("Address handle {} is an ip:port endpoint.", handle));
+            0
+        }
+        None => {
+            invalid_address(handle);
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ggrs_last_error_code;
+    use crate::tests::TestContext;
+
+    fn register(addr: &str) -> CAddressHandle {
+        let addr = std::ffi::CString::new(addr).unwrap();
+        unsafe { ggrs_address_register(addr.as_ptr()) }
+    }
+
+    fn to_string(handle: CAddressHandle) -> String {
+        let mut buffer = [0 as c_char; 64];
+        let length = unsafe { ggrs_address_to_string(handle, buffer.as_mut_ptr(), buffer.len()) };
+        let text = unsafe { CStr::from_ptr(buffer.as_ptr()) }.to_str().unwrap().to_string();
+        assert_eq!(text.len(), length);
+        text
+    }
+
+    #[test]
+    fn registry_gives_every_endpoint_one_handle() {
+        let mut registry = AddressRegistry::new();
+        let udp = Endpoint::Udp("127.0.0.1:7000".parse().unwrap());
+        let opaque = Endpoint::Opaque(vec![1, 2, 3]);
+
+        let a = registry.register(udp.clone());
+        let b = registry.register(opaque.clone());
+        assert!(a != INVALID_ADDRESS && b != INVALID_ADDRESS && a != b);
+        assert_eq!(registry.register(udp.clone()), a);
+        assert_eq!(registry.handle(&opaque), Some(b));
+        assert!(registry.endpoint(a) == Some(&udp));
+
+        assert!(registry.unregister(a));
+        assert!(!registry.unregister(a));
+        assert_eq!(registry.handle(&udp), None);
+        assert!(registry.endpoint(a).is_none());
+        // Handles are not reused
+        assert!(registry.register(udp) > b);
+    }
+
+    #[test]
+    fn addresses_are_parsed_and_written_back() {
+        let _ctx = TestContext::new();
+        let v4 = register("10.0.0.1:7000");
+        let v6 = register("[::1]:7001");
+        assert!(v4 != INVALID_ADDRESS && v6 != INVALID_ADDRESS);
+        assert_eq!(register("10.0.0.1:7000"), v4);
+        assert_eq!(to_string(v4), "10.0.0.1:7000");
+        assert_eq!(to_string(v6), "[::1]:7001");
+
+        // Truncated, the full length is still returned
+        let mut buffer = [0 as c_char; 4];
+        assert_eq!(unsafe { ggrs_address_to_string(v4, buffer.as_mut_ptr(), buffer.len()) }, 13);
+        assert_eq!(unsafe { CStr::from_ptr(buffer.as_ptr()) }.to_bytes(), b"10.");
+
+        assert_eq!(register("10.0.0.1"), INVALID_ADDRESS);
+        assert_eq!(ggrs_last_error_code(), CResult::InvalidAddress);
+        assert_eq!(unsafe { ggrs_address_register(std::ptr::null()) }, INVALID_ADDRESS);
+    }
+
+    #[test]
+    fn opaque_ids_are_copied_back() {
+        let _ctx = TestContext::new();
+        let id = [0xab, 0x01, 0xff];
+        let handle = unsafe { ggrs_address_register_bytes(id.as_ptr(), id.len()) };
+        assert_ne!(handle, INVALID_ADDRESS);
+        assert_eq!(to_string(handle), "ab01ff");
+
+        let mut buffer = [0u8; 8];
+        assert_eq!(unsafe { ggrs_address_bytes(handle, buffer.as_mut_ptr(), 2) }, 3);
+        assert_eq!(buffer, [0; 8]);
+        assert_eq!(unsafe { ggrs_address_bytes(handle, buffer.as_mut_ptr(), buffer.len()) }, 3);
+        assert_eq!(buffer[..3], id);
+
+        let udp = register("10.0.0.1:7000");
+        assert_eq!(unsafe { ggrs_address_bytes(udp, buffer.as_mut_ptr(), buffer.len()) }, 0);
+        assert_eq!(ggrs_last_error_code(), CResult::InvalidRequest);
+        assert_eq!(unsafe { ggrs_address_register_bytes(id.as_ptr(), 0) }, INVALID_ADDRESS);
+    }
+
+    #[test]
+    fn unregistered_addresses_are_invalid() {
+        let _ctx = TestContext::new();
+        let handle = register("10.0.0.1:7000");
+        assert_eq!(ggrs_address_unregister(handle), CResult::Ok);
+        assert_eq!(ggrs_address_unregister(handle), CResult::InvalidAddress);
+        assert_eq!(unsafe { ggrs_address_to_string(handle, std::ptr::null_mut(), 0) }, 0);
+        assert_eq!(ggrs_last_error_code(), CResult::InvalidAddress);
+    }
+}
@@ -0,0 +1,103 @@
+use ggrs::GgrsError;
+
+use std::cell::RefCell;
+use std::ffi::c_char;
+
+use crate::{CBuilderHandle, CSessionHandle};
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CResult {
+    Ok,
+    PredictionThreshold,
+    InvalidRequest,
+    MismatchedChecksum,
+    NotSynchronized,
+    SpectatorTooFarBehind,
+    InvalidHandle,
+    InvalidAddress,
+    InvalidMessage,
+    SocketError,
+    InternalError,
+    BufferTooSmall
+}
+
+// Converting a GgrsError records it as the last error of the calling thread
+impl From<&GgrsError> for CResult {
+    fn from(err: &GgrsError) -> Self {
+        let code = match err {
+            GgrsError::PredictionThreshold => CResult::PredictionThreshold,
+            GgrsError::InvalidRequest{..} => CResult::InvalidRequest,
+            GgrsError::MismatchedChecksum{..} => CResult::MismatchedChecksum,
+            GgrsError::NotSynchronized => CResult::NotSynchronized,
+            GgrsError::SpectatorTooFarBehind => CResult::SpectatorTooFarBehind
+        };
+        report(code, err.to_string())
+    }
+}
+impl From<GgrsError> for CResult {
+    fn from(err: GgrsError) -> Self {
+        CResult::from(&err)
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<(CResult, String)> = const { RefCell::new((CResult::Ok, String::new())) };
+}
+
+// Records a failure as the last error of the calling thread and passes the code through
+pub(crate) fn report(code: CResult, message: impl Into<String>) -> CResult {
+    LAST_ERROR.with(|e| *e.borrow_mut() = (code, message.into()));
+    code
+}
+
+pub(crate) fn invalid_handle(handle: CSessionHandle) -> CResult {
+    report(CResult::InvalidHandle, format!("Session handle {} does not exist.", handle))
+}
+
+pub(crate) fn invalid_builder(builder: CBuilderHandle) -> CResult {
+    report(CResult::InvalidHandle, format!("Builder handle {} does not exist.", builder))
+}
+
+// Panics must never unwind into C, anything that slips through is reported as on_panic instead
+pub(crate) fn catch_panic<R>(on_panic: R, f: impl FnOnce() -> R) -> R {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(r) => r,
+        Err(payload) => {
+            let message = match payload.downcast_ref::<&str>() {
+                Some(s) => s.to_string(),
+                None => match payload.downcast_ref::<String>() {
+                    Some(s) => s.clone(),
+                    None => "Unknown panic".to_owned()
+                }
+            };
+            report(CResult::InternalError, format!("Internal error: {}", message));
+            on_panic
+        }
+    }
+}
+
+// Copies text nul-terminated into buffer, truncating it if needed, and returns the full length of text
+pub(crate) unsafe fn copy_c_string(text: &[u8], buffer: *mut c_char, buffer_length: usize) -> usize {
+    if !buffer.is_null() && buffer_length > 0 {
+        let length = text.len().min(buffer_length - 1);
+        std::ptr::copy_nonoverlapping(text.as_ptr(), buffer as *mut u8, length);
+        *buffer.add(length) = 0;
+    }
+    text.len()
+}
+
+#[no_mangle]
+pub extern fn ggrs_last_error_code() -> CResult {
+    LAST_ERROR.with(|e| e.borrow().0)
+}
+
+/// # Safety
+/// `buffer` must be null or point to at least `buffer_length` writable bytes.
+///
+/// Copies the nul-terminated last error message into buffer, truncating it if needed.
+/// Returns the length of the full message without the terminator.
+#[no_mangle]
+pub unsafe extern fn ggrs_last_error_message(buffer: *mut c_char, buffer_length: usize) -> usize {
+    LAST_ERROR.with(|e| copy_c_string(e.borrow().1.as_bytes(), buffer, buffer_length))
+}
@@ -1,5 +1,11 @@
 use ggrs::*;
 use std::collections::{BTreeMap, VecDeque};
+use std::cell::RefCell;
+use std::ffi::c_void;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
 use std::vec::Vec;
 
 #[cfg(not(feature = "c_socket"))]
@@ -8,36 +14,49 @@ use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::ffi::{CStr, c_char};
 
 // Modules
+mod error;
 #[cfg(feature = "c_socket")]
 mod socket;
+#[cfg(feature = "c_socket")]
+mod address;
+
+use error::{CResult, catch_panic, invalid_builder, invalid_handle, report};
 
 // Types
 pub type CSessionHandle = u32;
+pub type CBuilderHandle = u32;
 pub type CPlayerHandle = usize;
 pub type CFrame = i32;
 pub type CInput = u32;
+pub type CChecksum = u64;
 
 // Consts
 pub const INVALID_HANDLE: CSessionHandle = 0;
+pub const DEFAULT_BUILDER: CBuilderHandle = 0;
+pub const NULL_FRAME: CFrame = -1;
+pub const CINPUT_BUFFER_SIZE: usize = 32;
+
+// Inputs are sent as fixed size buffers, only the first input_size bytes are used by the game
+type CInputBytes = [u8; CINPUT_BUFFER_SIZE];
 
 pub struct CConfig;
 #[cfg(not(feature = "c_socket"))]
 impl ggrs::Config for CConfig
 {
-    type Input = CInput;
-    type State = u32;
+    type Input = CInputBytes;
+    type State = Vec<u8>;
     type Address = SocketAddr;
 }
 #[cfg(feature = "c_socket")]
 impl ggrs::Config for CConfig
 {
-    type Input = CInput;
-    type State = u32;
+    type Input = CInputBytes;
+    type State = Vec<u8>;
     type Address = socket::CAddressHandle;
 }
 
 
-//#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct CSessionBuilderSettings{
     max_prediction: usize,
     fps: usize,
@@ -52,8 +71,22 @@ pub struct CSessionBuilderSettings{
     spectator_player_handles: Vec<(CPlayerHandle, SocketAddr)>,
 #[cfg(feature = "c_socket")]
     spectator_player_handles: Vec<(CPlayerHandle, socket::CAddressHandle)>,
+#[cfg(not(feature = "c_socket"))]
+    spectator_host: Option<SocketAddr>,
+#[cfg(feature = "c_socket")]
+    spectator_host: Option<socket::CAddressHandle>,
+#[cfg(feature = "c_socket")]
+    transport: Option<socket::CTransport>,
     host_port: u16,
-    input_delay: usize
+    input_delay: usize,
+    input_size: usize,
+    disconnect_timeout_ms: u64,
+    disconnect_notify_delay_ms: u64,
+    catchup_speed: usize,
+    max_frames_behind: usize,
+    check_distance: usize,
+    // 0 disables desync detection
+    desync_detection_interval: u32
 }
 impl CSessionBuilderSettings {
     const fn new() -> Self {
@@ -65,8 +98,18 @@ impl CSessionBuilderSettings {
             local_player_handles: Vec::new(),
             remote_player_handles: Vec::new(),
             spectator_player_handles: Vec::new(),
+            spectator_host: None,
+#[cfg(feature = "c_socket")]
+            transport: None,
             host_port: 30000,
-            input_delay: 2
+            input_delay: 2,
+            input_size: std::mem::size_of::<CInput>(),
+            disconnect_timeout_ms: 2000,
+            disconnect_notify_delay_ms: 500,
+            catchup_speed: 1,
+            max_frames_behind: 10,
+            check_distance: 2,
+            desync_detection_interval: 0
         }
     }
 }
@@ -80,6 +123,22 @@ pub enum CRequestType{
     None
 }
 
+#[repr(u8)]
+pub enum CInputStatus {
+    Confirmed,
+    Predicted,
+    Disconnected
+}
+impl From<InputStatus> for CInputStatus {
+    fn from(status: InputStatus) -> Self {
+        match status {
+            InputStatus::Confirmed => CInputStatus::Confirmed,
+            InputStatus::Predicted => CInputStatus::Predicted,
+            InputStatus::Disconnected => CInputStatus::Disconnected
+        }
+    }
+}
+
 #[repr(u8)]
 pub enum CSessionState {
     Synchronizing,
@@ -91,7 +150,14 @@ pub struct CRequest {
     request_type: CRequestType,
     frame: CFrame,
     player_handle: CPlayerHandle,
-    input: CInput
+    input: CInput,
+    input_bytes: [u8; CINPUT_BUFFER_SIZE],
+    input_length: u32,
+    input_status: CInputStatus,
+    // Number of SetInput requests for this AdvanceFrame, they are queued right before it
+    num_inputs: u32,
+    // Index of the first of those inputs in the inputs buffer of ggrs_session_next_requests
+    inputs_offset: u32
 }
 impl CRequest {
     const fn new_none() -> Self {
@@ -99,7 +165,12 @@ impl CRequest {
             request_type: CRequestType::None,
             frame: 0,
             player_handle: 0,
-            input: 0
+            input: 0,
+            input_bytes: [0; CINPUT_BUFFER_SIZE],
+            input_length: 0,
+            input_status: CInputStatus::Confirmed,
+            num_inputs: 0,
+            inputs_offset: 0
         }
     }
 
@@ -108,7 +179,12 @@ impl CRequest {
             request_type: CRequestType::SaveGameState,
             frame,
             player_handle: 0,
-            input: 0
+            input: 0,
+            input_bytes: [0; CINPUT_BUFFER_SIZE],
+            input_length: 0,
+            input_status: CInputStatus::Confirmed,
+            num_inputs: 0,
+            inputs_offset: 0
         }
     }
 
@@ -117,25 +193,40 @@ impl CRequest {
             request_type: CRequestType::LoadGameState,
             frame,
             player_handle: 0,
-            input: 0
+            input: 0,
+            input_bytes: [0; CINPUT_BUFFER_SIZE],
+            input_length: 0,
+            input_status: CInputStatus::Confirmed,
+            num_inputs: 0,
+            inputs_offset: 0
         }
     }
 
-    const fn new_advance() -> Self {
+    const fn new_advance(num_inputs: usize) -> Self {
         Self {
             request_type: CRequestType::AdvanceFrame,
             frame: 0,
             player_handle: 0,
-            input: 0
+            input: 0,
+            input_bytes: [0; CINPUT_BUFFER_SIZE],
+            input_length: 0,
+            input_status: CInputStatus::Confirmed,
+            num_inputs: num_inputs as u32,
+            inputs_offset: 0
         }
     }
 
-    const fn new_input(player_handle: CPlayerHandle, input: CInput) -> Self {
+    fn new_input(player_handle: CPlayerHandle, input_bytes: CInputBytes, input_length: usize, input_status: InputStatus) -> Self {
         Self {
             request_type: CRequestType::SetInput,
             frame: 0,
             player_handle,
-            input
+            input: CInput::from_le_bytes([input_bytes[0], input_bytes[1], input_bytes[2], input_bytes[3]]),
+            input_bytes,
+            input_length: input_length as u32,
+            input_status: input_status.into(),
+            num_inputs: 0,
+            inputs_offset: 0
         }
     }
 }
@@ -152,9 +243,86 @@ pub enum CEventTypes {
     None
 }
 
+#[cfg(not(feature = "c_socket"))]
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CSocketAddress {
+    is_ipv6: bool,
+    // IPv4 addresses only use the first 4 bytes
+    ip: [u8; 16],
+    port: u16
+}
+#[cfg(not(feature = "c_socket"))]
+impl From<&SocketAddr> for CSocketAddress {
+    fn from(addr: &SocketAddr) -> Self {
+        let mut ip = [0; 16];
+        match addr {
+            SocketAddr::V4(v4) => ip[..4].copy_from_slice(&v4.ip().octets()),
+            SocketAddr::V6(v6) => ip.copy_from_slice(&v6.ip().octets())
+        }
+        Self {
+            is_ipv6: addr.is_ipv6(),
+            ip,
+            port: addr.port()
+        }
+    }
+}
+
+// Address of a remote endpoint as reported to C
+#[cfg(not(feature = "c_socket"))]
+pub type CAddress = CSocketAddress;
+#[cfg(feature = "c_socket")]
+pub type CAddress = socket::CAddressHandle;
+
+#[cfg(not(feature = "c_socket"))]
+fn to_c_address(addr: &SocketAddr) -> CAddress {
+    CSocketAddress::from(addr)
+}
+#[cfg(feature = "c_socket")]
+fn to_c_address(addr: &socket::CAddressHandle) -> CAddress {
+    *addr
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CSynchronizingEvent {
+    addr: CAddress,
+    total: u32,
+    count: u32
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CAddressEvent {
+    addr: CAddress
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CNetworkInterruptedEvent {
+    addr: CAddress,
+    // Milliseconds until the endpoint is disconnected
+    disconnect_timeout: u64
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CDesyncDetectedEvent {
+    frame: CFrame,
+    local_checksum: CChecksum,
+    remote_checksum: CChecksum,
+    addr: CAddress
+}
+
 #[repr(C)]
 union CEventUnion {
+    synchronizing: CSynchronizingEvent,
+    synchronized: CAddressEvent,
+    disconnected: CAddressEvent,
+    network_interrupted: CNetworkInterruptedEvent,
+    network_resumed: CAddressEvent,
     skip_frames: u32,
+    desync_detected: CDesyncDetectedEvent,
     dummy: u8
 }
 
@@ -164,38 +332,38 @@ pub struct CEvent {
     data: CEventUnion
 }
 impl CEvent {
-    const fn new_synchronizing() -> Self {
+    const fn new_synchronizing(addr: CAddress, total: u32, count: u32) -> Self {
         Self {
             event_type: CEventTypes::Synchronizing,
-            data: CEventUnion { dummy: 0 }
+            data: CEventUnion { synchronizing: CSynchronizingEvent { addr, total, count } }
         }
     }
 
-    const fn new_synchronized() -> Self {
+    const fn new_synchronized(addr: CAddress) -> Self {
         Self {
             event_type: CEventTypes::Synchronized,
-            data: CEventUnion { dummy: 0 }
+            data: CEventUnion { synchronized: CAddressEvent { addr } }
         }
     }
 
-    const fn new_disconnected() -> Self {
+    const fn new_disconnected(addr: CAddress) -> Self {
         Self {
             event_type: CEventTypes::Disconnected,
-            data: CEventUnion { dummy: 0 }
+            data: CEventUnion { disconnected: CAddressEvent { addr } }
         }
     }
 
-    const fn new_network_interrupted() -> Self {
+    const fn new_network_interrupted(addr: CAddress, disconnect_timeout: u64) -> Self {
         Self {
             event_type: CEventTypes::NetworkInterrupted,
-            data: CEventUnion { dummy: 0 }
+            data: CEventUnion { network_interrupted: CNetworkInterruptedEvent { addr, disconnect_timeout } }
         }
     }
 
-    const fn new_network_resumed() -> Self {
+    const fn new_network_resumed(addr: CAddress) -> Self {
         Self {
             event_type: CEventTypes::NetworkResumed,
-            data: CEventUnion { dummy: 0 }
+            data: CEventUnion { network_resumed: CAddressEvent { addr } }
         }
     }
 
@@ -206,10 +374,10 @@ impl CEvent {
         }
     }
 
-    const fn new_desync_detected() -> Self {
+    const fn new_desync_detected(frame: CFrame, local_checksum: CChecksum, remote_checksum: CChecksum, addr: CAddress) -> Self {
         Self {
             event_type: CEventTypes::DesyncDetected,
-            data: CEventUnion { dummy: 0 }
+            data: CEventUnion { desync_detected: CDesyncDetectedEvent { frame, local_checksum, remote_checksum, addr } }
         }
     }
 
@@ -220,6 +388,60 @@ impl CEvent {
         }
     }
 }
+impl From<GgrsEvent<CConfig>> for CEvent {
+    fn from(event: GgrsEvent<CConfig>) -> Self {
+        match event {
+            GgrsEvent::Synchronizing{addr, total, count} => CEvent::new_synchronizing(to_c_address(&addr), total, count),
+            GgrsEvent::Synchronized{addr} => CEvent::new_synchronized(to_c_address(&addr)),
+            GgrsEvent::Disconnected{addr} => CEvent::new_disconnected(to_c_address(&addr)),
+            GgrsEvent::NetworkInterrupted{addr, disconnect_timeout} => CEvent::new_network_interrupted(to_c_address(&addr), disconnect_timeout as u64),
+            GgrsEvent::NetworkResumed{addr} => CEvent::new_network_resumed(to_c_address(&addr)),
+            GgrsEvent::WaitRecommendation{skip_frames} => CEvent::new_wait_recommendation(&skip_frames),
+            GgrsEvent::DesyncDetected{frame, local_checksum, remote_checksum, addr} => CEvent::new_desync_detected(frame, local_checksum as CChecksum, remote_checksum as CChecksum, to_c_address(&addr))
+        }
+    }
+}
+
+#[repr(C)]
+pub struct CNetworkStats {
+    send_queue_len: usize,
+    ping: u64,
+    kbps_sent: usize,
+    local_frames_behind: i32,
+    remote_frames_behind: i32
+}
+impl From<NetworkStats> for CNetworkStats {
+    fn from(stats: NetworkStats) -> Self {
+        Self {
+            send_queue_len: stats.send_queue_len,
+            ping: stats.ping as u64,
+            kbps_sent: stats.kbps_sent,
+            local_frames_behind: stats.local_frames_behind,
+            remote_frames_behind: stats.remote_frames_behind
+        }
+    }
+}
+
+// Called with the session handle after the session is unlocked, so they may call back into the library
+pub type CSaveCallback = Option<extern fn(user_data: *mut c_void, handle: CSessionHandle, frame: CFrame)>;
+pub type CLoadCallback = Option<extern fn(user_data: *mut c_void, handle: CSessionHandle, frame: CFrame)>;
+pub type CAdvanceCallback = Option<extern fn(user_data: *mut c_void, handle: CSessionHandle, inputs: *const CRequest, num_inputs: usize)>;
+
+#[derive(Clone, Copy)]
+struct CCallbacks {
+    save: extern fn(*mut c_void, CSessionHandle, CFrame),
+    load: extern fn(*mut c_void, CSessionHandle, CFrame),
+    advance: extern fn(*mut c_void, CSessionHandle, *const CRequest, usize),
+    user_data: *mut c_void
+}
+// user_data is only handed back to the game, on the thread that advances the session
+unsafe impl Send for CCallbacks {}
+
+struct PendingSave {
+    cell: GameStateCell<Vec<u8>>,
+    // GGRS may request the same frame more than once in a batch, each request is completed once
+    remaining: u32
+}
 
 enum CSessionType {
     SyncTest,
@@ -233,486 +455,1895 @@ enum CSession{
     Spectator(SpectatorSession<CConfig>)
 }
 
-static mut SB_SETTINGS: CSessionBuilderSettings = CSessionBuilderSettings::new();
-static mut SESSIONS: BTreeMap<CSessionHandle, CSession> = BTreeMap::new();
-static mut REQUESTS: BTreeMap<CSessionHandle, VecDeque<CRequest>> = BTreeMap::new();
-static mut EVENTS: BTreeMap<CSessionHandle, VecDeque<CEvent>> = BTreeMap::new();
-
-//////////////////////////////
-// SessionBuilder Functions //
-//////////////////////////////
-#[no_mangle]
-pub extern fn ggrs_builder_new() {
-    unsafe{
-        SB_SETTINGS = CSessionBuilderSettings::new();
+// Everything kept for one session between calls
+struct SessionData {
+    session: CSession,
+    requests: VecDeque<CRequest>,
+    events: VecDeque<CEvent>,
+    // Cells of SaveGameState requests, kept until the game completes the save with a checksum
+    pending_saves: BTreeMap<CFrame, PendingSave>,
+    // Sessions with desync detection need a checksum for every save before advancing
+    checksums_required: bool,
+    // Game state buffers handed out by LoadGameState requests
+    loaded_states: BTreeMap<CFrame, Vec<u8>>,
+    // Input size in bytes the session was built with
+    input_size: usize,
+    // Frame a SyncTest session was checking when it returned its last MismatchedChecksum error
+    checked_frame: CFrame,
+    // Frames whose checksums did not match in that error
+    mismatched_frames: Vec<CFrame>,
+    // Frame the game is at after handling all requests, GGRS does not expose it for SyncTest and Spectator sessions
+    current_frame: CFrame,
+    // Requests are passed to these instead of being queued for ggrs_session_next_ggrsRequest
+    callbacks: Option<CCallbacks>
+}
+impl SessionData {
+    fn new(session: CSession, input_size: usize, checksums_required: bool, current_frame: CFrame) -> Self {
+        Self {
+            session,
+            requests: VecDeque::new(),
+            events: VecDeque::new(),
+            pending_saves: BTreeMap::new(),
+            checksums_required,
+            loaded_states: BTreeMap::new(),
+            input_size,
+            checked_frame: NULL_FRAME,
+            mismatched_frames: Vec::new(),
+            current_frame,
+            callbacks: None
+        }
     }
 }
 
-#[no_mangle]
-pub extern fn ggrs_builder_with_fps(fps: usize) {
-    unsafe{
-        SB_SETTINGS.fps = fps;
+// All state of one library instance, sessions and builders of different contexts never see each other
+pub struct CContext {
+    // Settings of the default builder used by the ggrs_builder_* functions without a builder handle
+    sb_settings: Mutex<CSessionBuilderSettings>,
+    builders: Mutex<BTreeMap<CBuilderHandle, CSessionBuilderSettings>>,
+    builder_handle: AtomicU32,
+    sessions: Mutex<BTreeMap<CSessionHandle, SessionData>>,
+    session_handle: AtomicU32,
+#[cfg(feature = "c_socket")]
+    sockets: Mutex<BTreeMap<CSessionHandle, socket::SharedQueues>>,
+#[cfg(feature = "c_socket")]
+    addresses: Mutex<address::AddressRegistry>
+}
+impl CContext {
+    const fn new() -> Self {
+        Self {
+            sb_settings: Mutex::new(CSessionBuilderSettings::new()),
+            builders: Mutex::new(BTreeMap::new()),
+            builder_handle: AtomicU32::new(DEFAULT_BUILDER + 1),
+            sessions: Mutex::new(BTreeMap::new()),
+            session_handle: AtomicU32::new(INVALID_HANDLE + 1),
+#[cfg(feature = "c_socket")]
+            sockets: Mutex::new(BTreeMap::new()),
+#[cfg(feature = "c_socket")]
+            addresses: Mutex::new(address::AddressRegistry::new())
+        }
     }
 }
 
-#[no_mangle]
-pub extern fn ggrs_builder_with_max_prediction_window(window: usize) {
-    unsafe{
-        SB_SETTINGS.max_prediction = window;
-    }
+// Used by every thread that did not make another context current
+static DEFAULT_CONTEXT: CContext = CContext::new();
+
+thread_local! {
+    // Keeps the context alive while it is current, even if another thread frees it
+    static CURRENT_CONTEXT: RefCell<Option<Arc<CContext>>> = const { RefCell::new(None) };
 }
 
-#[no_mangle]
-pub extern fn ggrs_builder_with_num_players(num_players: usize) {
-    unsafe{
-        SB_SETTINGS.num_players = num_players;
+pub(crate) enum ContextRef {
+    Default,
+    Current(Arc<CContext>)
+}
+impl Deref for ContextRef {
+    type Target = CContext;
+
+    fn deref(&self) -> &CContext {
+        match self {
+            ContextRef::Default => &DEFAULT_CONTEXT,
+            ContextRef::Current(ctx) => ctx
+        }
     }
 }
 
-#[no_mangle]
-pub extern fn ggrs_builder_with_sparse_saving_mode(sparse_saving: bool) {
-    unsafe{
-        SB_SETTINGS.sparse_saving = sparse_saving;
+pub(crate) fn context() -> ContextRef {
+    match CURRENT_CONTEXT.with(|c| c.borrow().clone()) {
+        Some(ctx) => ContextRef::Current(ctx),
+        None => ContextRef::Default
     }
 }
 
+// A panic while locked is already reported by catch_panic, the data itself is still usable
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn with_session<R>(handle: CSessionHandle, on_invalid: R, f: impl FnOnce(&mut SessionData) -> R) -> R {
+    let result = match lock(&context().sessions).get_mut(&handle) {
+        Some(data) => f(data),
+        None => {
+            invalid_handle(handle);
+            on_invalid
+        }
+    };
+    // Messages for a transport are only sent once the sessions are unlocked, so its callbacks may call back into the library
+    #[cfg(feature = "c_socket")]
+    socket::flush_transport(handle);
+    result
+}
+
+//////////////////////////////
+// SessionBuilder Functions //
+//////////////////////////////
 #[no_mangle]
-pub extern fn ggrs_builder_with_input_delay(delay: usize) {
-    unsafe{
-        SB_SETTINGS.input_delay = delay;
-    }
+pub extern fn ggrs_builder_create() -> CBuilderHandle {
+    let ctx = context();
+    let handle = ctx.builder_handle.fetch_add(1, Ordering::Relaxed);
+    lock(&ctx.builders).insert(handle, CSessionBuilderSettings::new());
+    handle
 }
 
 #[no_mangle]
-pub extern fn ggrs_builder_add_local_player(player_handle: CPlayerHandle) {
-    unsafe{
-        SB_SETTINGS.local_player_handles.push(player_handle);
+pub extern fn ggrs_builder_free(builder: CBuilderHandle) {
+    lock(&context().builders).remove(&builder);
+}
+
+fn with_builder(builder: CBuilderHandle, f: impl FnOnce(&mut CSessionBuilderSettings) -> CResult) -> CResult {
+    if builder == DEFAULT_BUILDER {
+        return f(&mut lock(&context().sb_settings));
+    }
+    match lock(&context().builders).get_mut(&builder) {
+        Some(settings) => f(settings),
+        None => invalid_builder(builder)
     }
 }
 
-#[cfg(not(feature = "c_socket"))]
 #[no_mangle]
-pub extern fn ggrs_builder_add_remote_player_ipv4(player_handle: CPlayerHandle, ipv4: *const c_char, port: u16) {
-    unsafe{
-        let ipv4_cstr = CStr::from_ptr(ipv4);
-        let addr: SocketAddr = SocketAddr::V4(SocketAddrV4::new(ipv4_cstr.to_str().unwrap().parse().unwrap(), port));
-        SB_SETTINGS.remote_player_handles.push((player_handle, addr));
-    }
+pub extern fn ggrs_builder_new_ex(builder: CBuilderHandle) -> CResult {
+    with_builder(builder, |settings| {
+        *settings = CSessionBuilderSettings::new();
+        CResult::Ok
+    })
 }
 
-#[cfg(not(feature = "c_socket"))]
 #[no_mangle]
-pub extern fn ggrs_builder_add_remote_player_ipv6(player_handle: CPlayerHandle, ipv6: *const c_char, port: u16) {
-    unsafe{
-        let ipv6_cstr = CStr::from_ptr(ipv6);
-        let addr: SocketAddr = SocketAddr::V6(SocketAddrV6::new(ipv6_cstr.to_str().unwrap().parse().unwrap(), port, 0, 0));
-        SB_SETTINGS.remote_player_handles.push((player_handle, addr));
-    }
+pub extern fn ggrs_builder_new() {
+    ggrs_builder_new_ex(DEFAULT_BUILDER);
 }
 
-#[cfg(feature = "c_socket")]
 #[no_mangle]
-pub extern fn ggrs_builder_add_remote_player(player_handle: CPlayerHandle, addr_handle: socket::CAddressHandle) {
-    unsafe{
-        SB_SETTINGS.remote_player_handles.push((player_handle, addr_handle));
-    }
+pub extern fn ggrs_builder_with_fps_ex(builder: CBuilderHandle, fps: usize) -> CResult {
+    with_builder(builder, |settings| {
+        settings.fps = fps;
+        CResult::Ok
+    })
 }
 
-#[cfg(not(feature = "c_socket"))]
 #[no_mangle]
-pub extern fn ggrs_builder_add_spectator_player_ipv4(player_handle: CPlayerHandle, ipv4: *const c_char, port: u16) {
-    unsafe{
-        let ipv4_cstr = CStr::from_ptr(ipv4);
-        let addr: SocketAddr = SocketAddr::V4(SocketAddrV4::new(ipv4_cstr.to_str().unwrap().parse().unwrap(), port));
-        SB_SETTINGS.spectator_player_handles.push((player_handle, addr));
-    }
+pub extern fn ggrs_builder_with_fps(fps: usize) {
+    ggrs_builder_with_fps_ex(DEFAULT_BUILDER, fps);
 }
 
-#[cfg(not(feature = "c_socket"))]
 #[no_mangle]
-pub extern fn ggrs_builder_add_spectator_player_ipv6(player_handle: CPlayerHandle, ipv6: *const c_char, port: u16) {
-    unsafe{
-        let ipv6_cstr = CStr::from_ptr(ipv6);
-        let addr: SocketAddr = SocketAddr::V6(SocketAddrV6::new(ipv6_cstr.to_str().unwrap().parse().unwrap(), port, 0, 0));
-        SB_SETTINGS.spectator_player_handles.push((player_handle, addr));
-    }
+pub extern fn ggrs_builder_with_max_prediction_window_ex(builder: CBuilderHandle, window: usize) -> CResult {
+    with_builder(builder, |settings| {
+        settings.max_prediction = window;
+        CResult::Ok
+    })
 }
 
-#[cfg(feature = "c_socket")]
 #[no_mangle]
-pub extern fn ggrs_builder_add_spectator_player(player_handle: CPlayerHandle, addr_handle: socket::CAddressHandle) {
-    unsafe{
-        SB_SETTINGS.spectator_player_handles.push((player_handle, addr_handle));
-    }
+pub extern fn ggrs_builder_with_max_prediction_window(window: usize) {
+    ggrs_builder_with_max_prediction_window_ex(DEFAULT_BUILDER, window);
 }
 
 #[no_mangle]
-pub extern fn ggrs_builder_set_host_port(port: u16) {
-    unsafe{
-        SB_SETTINGS.host_port = port;
-    }
+pub extern fn ggrs_builder_with_num_players_ex(builder: CBuilderHandle, num_players: usize) -> CResult {
+    with_builder(builder, |settings| {
+        settings.num_players = num_players;
+        CResult::Ok
+    })
 }
 
-fn build_session(session_type: CSessionType) -> Result<CSessionHandle, GgrsError> {
-    static mut SESSION_HANDLE: CSessionHandle = 1;
+#[no_mangle]
+pub extern fn ggrs_builder_with_num_players(num_players: usize) {
+    ggrs_builder_with_num_players_ex(DEFAULT_BUILDER, num_players);
+}
 
-    let handle: CSessionHandle;
-    let mut sb: SessionBuilder<CConfig>;
+#[no_mangle]
+pub extern fn ggrs_builder_with_sparse_saving_mode_ex(builder: CBuilderHandle, sparse_saving: bool) -> CResult {
+    with_builder(builder, |settings| {
+        settings.sparse_saving = sparse_saving;
+        CResult::Ok
+    })
+}
 
-    unsafe{
-        handle = SESSION_HANDLE;
-        SESSION_HANDLE += 1;
-        sb = SessionBuilder::<CConfig>::new()
-            .with_fps(SB_SETTINGS.fps)?
-            .with_max_prediction_window(SB_SETTINGS.max_prediction)?
-            .with_num_players(SB_SETTINGS.num_players)
-            .with_sparse_saving_mode(SB_SETTINGS.sparse_saving)
-            .with_input_delay(SB_SETTINGS.input_delay);
+#[no_mangle]
+pub extern fn ggrs_builder_with_sparse_saving_mode(sparse_saving: bool) {
+    ggrs_builder_with_sparse_saving_mode_ex(DEFAULT_BUILDER, sparse_saving);
+}
 
-        // Add local player handles
-        for i in 0..SB_SETTINGS.local_player_handles.len() {
-            sb = sb.add_player(PlayerType::Local, SB_SETTINGS.local_player_handles[i])?;
-        }
+#[no_mangle]
+pub extern fn ggrs_builder_with_input_delay_ex(builder: CBuilderHandle, delay: usize) -> CResult {
+    with_builder(builder, |settings| {
+        settings.input_delay = delay;
+        CResult::Ok
+    })
+}
 
-        // Add remote player handles
-        for i in 0..SB_SETTINGS.remote_player_handles.len() {
-            sb = sb.add_player(PlayerType::Remote(SB_SETTINGS.remote_player_handles[i].1), SB_SETTINGS.remote_player_handles[i].0)?;
-        }
+#[no_mangle]
+pub extern fn ggrs_builder_with_input_delay(delay: usize) {
+    ggrs_builder_with_input_delay_ex(DEFAULT_BUILDER, delay);
+}
 
-        // Add spectator player handles
-        for i in 0..SB_SETTINGS.spectator_player_handles.len() {
-            sb = sb.add_player(PlayerType::Spectator(SB_SETTINGS.spectator_player_handles[i].1), SB_SETTINGS.spectator_player_handles[i].0)?;
-        }
-    }
+#[no_mangle]
+pub extern fn ggrs_builder_with_input_size_ex(builder: CBuilderHandle, size: usize) -> CResult {
+    with_builder(builder, |settings| {
+        settings.input_size = size;
+        CResult::Ok
+    })
+}
 
-    match session_type {
-        CSessionType::SyncTest => {
-            let sess = sb.start_synctest_session()?;
-            unsafe{
-                SESSIONS.insert(handle, CSession::SyncTest(sess));
-                REQUESTS.insert(handle, VecDeque::new());
-                EVENTS.insert(handle, VecDeque::new());
-            }
-        }
-        CSessionType::P2P => {
-            unsafe{
-                #[cfg(not(feature = "c_socket"))]
-                let sess = sb.start_p2p_session(UdpNonBlockingSocket::bind_to_port(SB_SETTINGS.host_port).unwrap())?;
-                #[cfg(feature = "c_socket")]
-                let sess = sb.start_p2p_session(socket::CSocket::new(handle))?;
-                SESSIONS.insert(handle, CSession::P2P(sess));
-                REQUESTS.insert(handle, VecDeque::new());
-                EVENTS.insert(handle, VecDeque::new());
-                #[cfg(feature = "c_socket")]
-                socket::SOCKET_IN.insert(handle, VecDeque::new());
-                #[cfg(feature = "c_socket")]
-                socket::SOCKET_OUT.insert(handle, VecDeque::new());
-            }
-        }
-        CSessionType::Spectator => {
-            unsafe{
-                #[cfg(not(feature = "c_socket"))]
-                let sess = sb.start_spectator_session(SB_SETTINGS.remote_player_handles[0].1, UdpNonBlockingSocket::bind_to_port(SB_SETTINGS.host_port).unwrap());
-                #[cfg(feature = "c_socket")]
-                let sess = sb.start_spectator_session(SB_SETTINGS.remote_player_handles[0].1, socket::CSocket::new(handle));
-                SESSIONS.insert(handle, CSession::Spectator(sess));
-                REQUESTS.insert(handle, VecDeque::new());
-                EVENTS.insert(handle, VecDeque::new());
-                #[cfg(feature = "c_socket")]
-                socket::SOCKET_IN.insert(handle, VecDeque::new());
-                #[cfg(feature = "c_socket")]
-                socket::SOCKET_OUT.insert(handle, VecDeque::new());
-            }
-        }
-    }
+#[no_mangle]
+pub extern fn ggrs_builder_with_input_size(size: usize) {
+    ggrs_builder_with_input_size_ex(DEFAULT_BUILDER, size);
+}
 
-    Ok(handle)
+#[no_mangle]
+pub extern fn ggrs_builder_with_disconnect_timeout_ex(builder: CBuilderHandle, timeout_ms: u64) -> CResult {
+    with_builder(builder, |settings| {
+        settings.disconnect_timeout_ms = timeout_ms;
+        CResult::Ok
+    })
 }
 
 #[no_mangle]
-pub extern fn ggrs_builder_start_synctest_session() -> CSessionHandle{
-    match build_session(CSessionType::SyncTest) {
-        Ok(h) => h,
-        Err(_) => INVALID_HANDLE
-    }
+pub extern fn ggrs_builder_with_disconnect_timeout(timeout_ms: u64) {
+    ggrs_builder_with_disconnect_timeout_ex(DEFAULT_BUILDER, timeout_ms);
 }
 
 #[no_mangle]
-pub extern fn ggrs_builder_start_p2p_session() -> CSessionHandle{
-    match build_session(CSessionType::P2P) {
-        Ok(h) => h,
-        Err(_) => INVALID_HANDLE
-    }
+pub extern fn ggrs_builder_with_disconnect_notify_delay_ex(builder: CBuilderHandle, notify_delay_ms: u64) -> CResult {
+    with_builder(builder, |settings| {
+        settings.disconnect_notify_delay_ms = notify_delay_ms;
+        CResult::Ok
+    })
 }
 
 #[no_mangle]
-pub extern fn ggrs_builder_start_spectator_session() -> CSessionHandle{
-    match build_session(CSessionType::Spectator) {
-        Ok(h) => h,
-        Err(_) => INVALID_HANDLE
-    }
+pub extern fn ggrs_builder_with_disconnect_notify_delay(notify_delay_ms: u64) {
+    ggrs_builder_with_disconnect_notify_delay_ex(DEFAULT_BUILDER, notify_delay_ms);
 }
 
 #[no_mangle]
-pub extern fn ggrs_session_poll_remote_clients(handle: CSessionHandle)
-{
-    unsafe {
-        match SESSIONS.get_mut(&handle) {
-            Some(sess) => {
-                match sess {
-                    CSession::SyncTest(_) => {
-                        return;
-                    }
-                    CSession::P2P(p2p) => {
-                        p2p.poll_remote_clients();
-                    }
-                    CSession::Spectator(spectator) => {
-                        spectator.poll_remote_clients();
-                    }
-                };
-            }
-            None => return
-        };
-    }
+pub extern fn ggrs_builder_with_catchup_speed_ex(builder: CBuilderHandle, catchup_speed: usize) -> CResult {
+    with_builder(builder, |settings| {
+        settings.catchup_speed = catchup_speed;
+        CResult::Ok
+    })
 }
 
 #[no_mangle]
-pub extern fn ggrs_session_current_state(handle: CSessionHandle) -> CSessionState
-{
-    unsafe {
-        match SESSIONS.get_mut(&handle) {
-            Some(sess) => {
-                match sess {
-                    CSession::SyncTest(_) => {
-                        CSessionState::Running
-                    }
-                    CSession::P2P(p2p) => {
-                        match p2p.current_state() {
-                            SessionState::Synchronizing => CSessionState::Synchronizing,
-                            SessionState::Running => CSessionState::Running
-                        }
-                    }
-                    CSession::Spectator(spectator) => {
-                        match spectator.current_state() {
-                            SessionState::Synchronizing => CSessionState::Synchronizing,
-                            SessionState::Running => CSessionState::Running
-                        }
-                    }
-                }
-            }
-            None => CSessionState::Running
-        }
-    }
+pub extern fn ggrs_builder_with_catchup_speed(catchup_speed: usize) {
+    ggrs_builder_with_catchup_speed_ex(DEFAULT_BUILDER, catchup_speed);
 }
 
 #[no_mangle]
-pub extern fn ggrs_session_frames_ahead(handle: CSessionHandle) -> i32
-{
-    unsafe {
-        match SESSIONS.get_mut(&handle) {
-            Some(sess) => {
-                match sess {
-                    CSession::SyncTest(_) => {
-                        0
-                    }
-                    CSession::P2P(p2p) => {
-                        p2p.frames_ahead()
-                    }
-                    CSession::Spectator(_) => {
-                        0
-                    }
-                }
-            }
-            None => 0
-        }
-    }
+pub extern fn ggrs_builder_with_max_frames_behind_ex(builder: CBuilderHandle, max_frames_behind: usize) -> CResult {
+    with_builder(builder, |settings| {
+        settings.max_frames_behind = max_frames_behind;
+        CResult::Ok
+    })
 }
 
 #[no_mangle]
-pub extern fn ggrs_session_add_local_input(handle: CSessionHandle, player_handle: CPlayerHandle, input: CInput) {
-    unsafe {
-        match SESSIONS.get_mut(&handle) {
-            Some(sess) => {
-                match sess {
-                    CSession::SyncTest(st) => {
-                        st.add_local_input(player_handle, input).unwrap();
-                    }
-                    CSession::P2P(p2p) => {
-                        p2p.add_local_input(player_handle, input).unwrap();
-                    }
-                    CSession::Spectator(_) => {
-                        return;
-                    }
-                };
-            }
-            None => return
-        };
-    }
+pub extern fn ggrs_builder_with_max_frames_behind(max_frames_behind: usize) {
+    ggrs_builder_with_max_frames_behind_ex(DEFAULT_BUILDER, max_frames_behind);
 }
 
 #[no_mangle]
-pub extern fn ggrs_session_advance_frame(handle: CSessionHandle) {
-    let ggrs_requests: Vec<GgrsRequest<CConfig>>;
-    let c_requests: &mut VecDeque<CRequest>;
+pub extern fn ggrs_builder_with_check_distance_ex(builder: CBuilderHandle, check_distance: usize) -> CResult {
+    with_builder(builder, |settings| {
+        settings.check_distance = check_distance;
+        CResult::Ok
+    })
+}
 
-    // Get ggrs_requests and c_requests
-    unsafe {
-        match SESSIONS.get_mut(&handle) {
-            Some(sess) => {
-                match sess {
-                    CSession::SyncTest(st) => {
-                        match st.advance_frame() {
-                            Ok(req) => {
-                                ggrs_requests = req
-                            }
-                            Err(_) => return
-                        }
-                    }
-                    CSession::P2P(p2p) => {
-                        match p2p.advance_frame() {
-                            Ok(req) => {
-                                ggrs_requests = req
-                            }
-                            Err(_) => return
-                        }
-                    }
-                    CSession::Spectator(spectator) => {
-                        match spectator.advance_frame() {
-                            Ok(req) => {
-                                ggrs_requests = req
-                            }
-                            Err(_) => return
-                        }
-                    }
-                };
-            }
-            None => return
-        };
+#[no_mangle]
+pub extern fn ggrs_builder_with_check_distance(check_distance: usize) {
+    ggrs_builder_with_check_distance_ex(DEFAULT_BUILDER, check_distance);
+}
 
-        match REQUESTS.get_mut(&handle) {
-            Some(reqs) => {
-                c_requests = reqs;
-            }
-            None => return
-        }
-    }
+#[no_mangle]
+pub extern fn ggrs_builder_with_desync_detection_mode_ex(builder: CBuilderHandle, interval: u32) -> CResult {
+    with_builder(builder, |settings| {
+        settings.desync_detection_interval = interval;
+        CResult::Ok
+    })
+}
 
-    // Convert requests to GgrsCppRequest's
-    for req in ggrs_requests {
-        match req {
-            GgrsRequest::SaveGameState{ frame, cell } => {
-                cell.save(frame, None, None);
-                c_requests.push_back(CRequest::new_save(frame));
-            }
+#[no_mangle]
+pub extern fn ggrs_builder_with_desync_detection_mode(interval: u32) {
+    ggrs_builder_with_desync_detection_mode_ex(DEFAULT_BUILDER, interval);
+}
 
-            GgrsRequest::LoadGameState { frame, cell } => {
-                cell.load();
-                c_requests.push_back(CRequest::new_load(frame));
-            }
+#[no_mangle]
+pub extern fn ggrs_builder_add_local_player_ex(builder: CBuilderHandle, player_handle: CPlayerHandle) -> CResult {
+    with_builder(builder, |settings| {
+        settings.local_player_handles.push(player_handle);
+        CResult::Ok
+    })
+}
 
-            GgrsRequest::AdvanceFrame { inputs } => {
-                for i in 0..inputs.len() {
-                    c_requests.push_back(CRequest::new_input(i, inputs[i].0));
-                }
-                
-                c_requests.push_back(CRequest::new_advance());
-            }
-        }
+#[no_mangle]
+pub extern fn ggrs_builder_add_local_player(player_handle: CPlayerHandle) {
+    ggrs_builder_add_local_player_ex(DEFAULT_BUILDER, player_handle);
+}
 
+#[cfg(not(feature = "c_socket"))]
+unsafe fn parse_ip<T: std::str::FromStr>(ip: *const c_char) -> Option<T> {
+    if ip.is_null() {
+        return None;
     }
+    CStr::from_ptr(ip).to_str().ok()?.parse().ok()
+}
 
+/// # Safety
+/// `ipv4` must be null or a valid nul-terminated string.
+#[cfg(not(feature = "c_socket"))]
+#[no_mangle]
+pub unsafe extern fn ggrs_builder_add_remote_player_ipv4_ex(builder: CBuilderHandle, player_handle: CPlayerHandle, ipv4: *const c_char, port: u16) -> CResult {
+    let ip = match parse_ip(ipv4) {
+        Some(ip) => ip,
+        None => return report(CResult::InvalidAddress, "Unable to parse IP address.")
+    };
+    with_builder(builder, |settings| {
+        settings.remote_player_handles.push((player_handle, SocketAddr::V4(SocketAddrV4::new(ip, port))));
+        CResult::Ok
+    })
 }
 
+/// # Safety
+/// `ipv4` must be null or a valid nul-terminated string.
+#[cfg(not(feature = "c_socket"))]
 #[no_mangle]
-pub extern fn ggrs_session_next_ggrsRequest(handle: CSessionHandle) -> CRequest {
-    unsafe {
-        match REQUESTS.get_mut(&handle) {
-            Some(reqs) => {
-                match reqs.pop_front() {
-                    Some(r) => return r,
-                    None => return CRequest::new_none()
-                };
-            }
+pub unsafe extern fn ggrs_builder_add_remote_player_ipv4(player_handle: CPlayerHandle, ipv4: *const c_char, port: u16) -> CResult {
+    ggrs_builder_add_remote_player_ipv4_ex(DEFAULT_BUILDER, player_handle, ipv4, port)
+}
 
-            None => return CRequest::new_none()
-        };
-    }
+/// # Safety
+/// `ipv6` must be null or a valid nul-terminated string.
+#[cfg(not(feature = "c_socket"))]
+#[no_mangle]
+pub unsafe extern fn ggrs_builder_add_remote_player_ipv6_ex(builder: CBuilderHandle, player_handle: CPlayerHandle, ipv6: *const c_char, port: u16) -> CResult {
+    let ip = match parse_ip(ipv6) {
+        Some(ip) => ip,
+        None => return report(CResult::InvalidAddress, "Unable to parse IP address.")
+    };
+    with_builder(builder, |settings| {
+        settings.remote_player_handles.push((player_handle, SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0))));
+        CResult::Ok
+    })
 }
 
+/// # Safety
+/// `ipv6` must be null or a valid nul-terminated string.
+#[cfg(not(feature = "c_socket"))]
 #[no_mangle]
-pub extern fn ggrs_session_process_events(handle: CSessionHandle) -> () {
-    let c_events: &mut VecDeque<CEvent>;
+pub unsafe extern fn ggrs_builder_add_remote_player_ipv6(player_handle: CPlayerHandle, ipv6: *const c_char, port: u16) -> CResult {
+    ggrs_builder_add_remote_player_ipv6_ex(DEFAULT_BUILDER, player_handle, ipv6, port)
+}
 
-    unsafe {
-        match EVENTS.get_mut(&handle) {
-            Some(events) => {
-                c_events = events;
+#[cfg(feature = "c_socket")]
+#[no_mangle]
+pub extern fn ggrs_builder_add_remote_player_ex(builder: CBuilderHandle, player_handle: CPlayerHandle, addr_handle: socket::CAddressHandle) -> CResult {
+    with_builder(builder, |settings| {
+        settings.remote_player_handles.push((player_handle, addr_handle));
+        CResult::Ok
+    })
+}
+
+#[cfg(feature = "c_socket")]
+#[no_mangle]
+pub extern fn ggrs_builder_add_remote_player(player_handle: CPlayerHandle, addr_handle: socket::CAddressHandle) {
+    ggrs_builder_add_remote_player_ex(DEFAULT_BUILDER, player_handle, addr_handle);
+}
+
+/// # Safety
+/// `ipv4` must be null or a valid nul-terminated string.
+#[cfg(not(feature = "c_socket"))]
+#[no_mangle]
+pub unsafe extern fn ggrs_builder_add_spectator_player_ipv4_ex(builder: CBuilderHandle, player_handle: CPlayerHandle, ipv4: *const c_char, port: u16) -> CResult {
+    let ip = match parse_ip(ipv4) {
+        Some(ip) => ip,
+        None => return report(CResult::InvalidAddress, "Unable to parse IP address.")
+    };
+    with_builder(builder, |settings| {
+        settings.spectator_player_handles.push((player_handle, SocketAddr::V4(SocketAddrV4::new(ip, port))));
+        CResult::Ok
+    })
+}
+
+/// # Safety
+/// `ipv4` must be null or a valid nul-terminated string.
+#[cfg(not(feature = "c_socket"))]
+#[no_mangle]
+pub unsafe extern fn ggrs_builder_add_spectator_player_ipv4(player_handle: CPlayerHandle, ipv4: *const c_char, port: u16) -> CResult {
+    ggrs_builder_add_spectator_player_ipv4_ex(DEFAULT_BUILDER, player_handle, ipv4, port)
+}
+
+/// # Safety
+/// `ipv6` must be null or a valid nul-terminated string.
+#[cfg(not(feature = "c_socket"))]
+#[no_mangle]
+pub unsafe extern fn ggrs_builder_add_spectator_player_ipv6_ex(builder: CBuilderHandle, player_handle: CPlayerHandle, ipv6: *const c_char, port: u16) -> CResult {
+    let ip = match parse_ip(ipv6) {
+        Some(ip) => ip,
+        None => return report(CResult::InvalidAddress, "Unable to parse IP address.")
+    };
+    with_builder(builder, |settings| {
+        settings.spectator_player_handles.push((player_handle, SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0))));
+        CResult::Ok
+    })
+}
+
+/// # Safety
+/// `ipv6` must be null or a valid nul-terminated string.
+#[cfg(not(feature = "c_socket"))]
+#[no_mangle]
+pub unsafe extern fn ggrs_builder_add_spectator_player_ipv6(player_handle: CPlayerHandle, ipv6: *const c_char, port: u16) -> CResult {
+    ggrs_builder_add_spectator_player_ipv6_ex(DEFAULT_BUILDER, player_handle, ipv6, port)
+}
+
+#[cfg(feature = "c_socket")]
+#[no_mangle]
+pub extern fn ggrs_builder_add_spectator_player_ex(builder: CBuilderHandle, player_handle: CPlayerHandle, addr_handle: socket::CAddressHandle) -> CResult {
+    with_builder(builder, |settings| {
+        settings.spectator_player_handles.push((player_handle, addr_handle));
+        CResult::Ok
+    })
+}
+
+#[cfg(feature = "c_socket")]
+#[no_mangle]
+pub extern fn ggrs_builder_add_spectator_player(player_handle: CPlayerHandle, addr_handle: socket::CAddressHandle) {
+    ggrs_builder_add_spectator_player_ex(DEFAULT_BUILDER, player_handle, addr_handle);
+}
+
+/// # Safety
+/// `ipv4` must be null or a valid nul-terminated string.
+#[cfg(not(feature = "c_socket"))]
+#[no_mangle]
+pub unsafe extern fn ggrs_builder_set_spectator_host_ipv4_ex(builder: CBuilderHandle, ipv4: *const c_char, port: u16) -> CResult {
+    let ip = match parse_ip(ipv4) {
+        Some(ip) => ip,
+        None => return report(CResult::InvalidAddress, "Unable to parse IP address.")
+    };
+    with_builder(builder, |settings| {
+        settings.spectator_host = Some(SocketAddr::V4(SocketAddrV4::new(ip, port)));
+        CResult::Ok
+    })
+}
+
+/// # Safety
+/// `ipv4` must be null or a valid nul-terminated string.
+#[cfg(not(feature = "c_socket"))]
+#[no_mangle]
+pub unsafe extern fn ggrs_builder_set_spectator_host_ipv4(ipv4: *const c_char, port: u16) -> CResult {
+    ggrs_builder_set_spectator_host_ipv4_ex(DEFAULT_BUILDER, ipv4, port)
+}
+
+/// # Safety
+/// `ipv6` must be null or a valid nul-terminated string.
+#[cfg(not(feature = "c_socket"))]
+#[no_mangle]
+pub unsafe extern fn ggrs_builder_set_spectator_host_ipv6_ex(builder: CBuilderHandle, ipv6: *const c_char, port: u16) -> CResult {
+    let ip = match parse_ip(ipv6) {
+        Some(ip) => ip,
+        None => return report(CResult::InvalidAddress, "Unable to parse IP address.")
+    };
+    with_builder(builder, |settings| {
+        settings.spectator_host = Some(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0)));
+        CResult::Ok
+    })
+}
+
+/// # Safety
+/// `ipv6` must be null or a valid nul-terminated string.
+#[cfg(not(feature = "c_socket"))]
+#[no_mangle]
+pub unsafe extern fn ggrs_builder_set_spectator_host_ipv6(ipv6: *const c_char, port: u16) -> CResult {
+    ggrs_builder_set_spectator_host_ipv6_ex(DEFAULT_BUILDER, ipv6, port)
+}
+
+#[cfg(feature = "c_socket")]
+#[no_mangle]
+pub extern fn ggrs_builder_set_spectator_host_ex(builder: CBuilderHandle, addr_handle: socket::CAddressHandle) -> CResult {
+    with_builder(builder, |settings| {
+        settings.spectator_host = Some(addr_handle);
+        CResult::Ok
+    })
+}
+
+#[cfg(feature = "c_socket")]
+#[no_mangle]
+pub extern fn ggrs_builder_set_spectator_host(addr_handle: socket::CAddressHandle) -> CResult {
+    ggrs_builder_set_spectator_host_ex(DEFAULT_BUILDER, addr_handle)
+}
+
+/// Sessions built afterwards send and receive through transport instead of the ggrs_socket_* message queues.
+/// Its callbacks are called while no session is locked, so they may call back into the library, e.g. ggrs_socket_in_message.
+#[cfg(feature = "c_socket")]
+#[no_mangle]
+pub extern fn ggrs_builder_set_transport_ex(builder: CBuilderHandle, transport: &socket::CTransport) -> CResult {
+    if transport.validate() != CResult::Ok {
+        return CResult::InvalidRequest;
+    }
+    with_builder(builder, |settings| {
+        settings.transport = Some(*transport);
+        CResult::Ok
+    })
+}
+
+#[cfg(feature = "c_socket")]
+#[no_mangle]
+pub extern fn ggrs_builder_set_transport(transport: &socket::CTransport) -> CResult {
+    ggrs_builder_set_transport_ex(DEFAULT_BUILDER, transport)
+}
+
+#[no_mangle]
+pub extern fn ggrs_builder_set_host_port_ex(builder: CBuilderHandle, port: u16) -> CResult {
+    with_builder(builder, |settings| {
+        settings.host_port = port;
+        CResult::Ok
+    })
+}
+
+#[no_mangle]
+pub extern fn ggrs_builder_set_host_port(port: u16) {
+    ggrs_builder_set_host_port_ex(DEFAULT_BUILDER, port);
+}
+
+fn build_session(builder: CBuilderHandle, session_type: CSessionType) -> Result<CSessionHandle, CResult> {
+    let ctx = context();
+
+    // Copy the settings so the builder is not locked while sockets are bound
+    let settings = if builder == DEFAULT_BUILDER {
+        lock(&ctx.sb_settings).clone()
+    } else {
+        match lock(&ctx.builders).get(&builder) {
+            Some(settings) => settings.clone(),
+            None => return Err(invalid_builder(builder))
+        }
+    };
+
+    if settings.input_size == 0 || settings.input_size > CINPUT_BUFFER_SIZE {
+        return Err(report(CResult::InvalidRequest, format!("Input size must be between 1 and {} bytes.", CINPUT_BUFFER_SIZE)));
+    }
+
+    let handle = ctx.session_handle.fetch_add(1, Ordering::Relaxed);
+    let mut sb = SessionBuilder::<CConfig>::new()
+        .with_fps(settings.fps)?
+        .with_max_prediction_window(settings.max_prediction)?
+        .with_num_players(settings.num_players)
+        .with_sparse_saving_mode(settings.sparse_saving)
+        .with_input_delay(settings.input_delay)
+        .with_disconnect_timeout(Duration::from_millis(settings.disconnect_timeout_ms))
+        .with_disconnect_notify_delay(Duration::from_millis(settings.disconnect_notify_delay_ms))
+        .with_max_frames_behind(settings.max_frames_behind)?
+        .with_catchup_speed(settings.catchup_speed)?
+        .with_check_distance(settings.check_distance);
+
+    if settings.desync_detection_interval > 0 {
+        // GGRS expects a saved cell for every checksum interval frame, sparse saving skips most of them
+        if settings.sparse_saving {
+            return Err(report(CResult::InvalidRequest, "Desync detection cannot be combined with sparse saving."));
+        }
+        sb = sb.with_desync_detection_mode(DesyncDetection::On { interval: settings.desync_detection_interval });
+    }
+
+    // Add local player handles
+    for i in 0..settings.local_player_handles.len() {
+        sb = sb.add_player(PlayerType::Local, settings.local_player_handles[i])?;
+    }
+
+    // Add remote player handles
+    for i in 0..settings.remote_player_handles.len() {
+        sb = sb.add_player(PlayerType::Remote(settings.remote_player_handles[i].1), settings.remote_player_handles[i].0)?;
+    }
+
+    // Add spectator player handles
+    for i in 0..settings.spectator_player_handles.len() {
+        sb = sb.add_player(PlayerType::Spectator(settings.spectator_player_handles[i].1), settings.spectator_player_handles[i].0)?;
+    }
+
+    let data = match session_type {
+        CSessionType::SyncTest => {
+            let sess = sb.start_synctest_session()?;
+            SessionData::new(CSession::SyncTest(sess), settings.input_size, false, 0)
+        }
+        CSessionType::P2P => {
+            #[cfg(not(feature = "c_socket"))]
+            let sess = sb.start_p2p_session(UdpNonBlockingSocket::bind_to_port(settings.host_port).map_err(|e| report(CResult::SocketError, format!("Unable to bind port {}: {}", settings.host_port, e)))?)?;
+            #[cfg(feature = "c_socket")]
+            let sess = {
+                let queues = socket::new_queues(settings.transport);
+                let sess = sb.start_p2p_session(socket::CSocket::new(queues.clone()))?;
+                // Only opened once the session started, so a failed start leaves no socket behind
+                socket::open(handle, queues);
+                sess
+            };
+            SessionData::new(CSession::P2P(sess), settings.input_size, settings.desync_detection_interval > 0, 0)
+        }
+        CSessionType::Spectator => {
+            let host = match settings.spectator_host {
+                Some(h) => h,
+                None => return Err(report(CResult::InvalidRequest, "Spectator sessions require the address of the host to be set on the builder."))
+            };
+            #[cfg(not(feature = "c_socket"))]
+            let sess = sb.start_spectator_session(host, UdpNonBlockingSocket::bind_to_port(settings.host_port).map_err(|e| report(CResult::SocketError, format!("Unable to bind port {}: {}", settings.host_port, e)))?);
+            #[cfg(feature = "c_socket")]
+            let sess = {
+                let queues = socket::new_queues(settings.transport);
+                let sess = sb.start_spectator_session(host, socket::CSocket::new(queues.clone()));
+                socket::open(handle, queues);
+                sess
+            };
+            SessionData::new(CSession::Spectator(sess), settings.input_size, false, NULL_FRAME)
+        }
+    };
+    lock(&ctx.sessions).insert(handle, data);
+
+    Ok(handle)
+}
+
+#[no_mangle]
+pub extern fn ggrs_builder_start_synctest_session_ex(builder: CBuilderHandle) -> CSessionHandle{
+    match catch_panic(Err(CResult::InternalError), || build_session(builder, CSessionType::SyncTest)) {
+        Ok(h) => h,
+        Err(_) => INVALID_HANDLE
+    }
+}
+
+#[no_mangle]
+pub extern fn ggrs_builder_start_synctest_session() -> CSessionHandle{
+    ggrs_builder_start_synctest_session_ex(DEFAULT_BUILDER)
+}
+
+#[no_mangle]
+pub extern fn ggrs_builder_start_p2p_session_ex(builder: CBuilderHandle) -> CSessionHandle{
+    match catch_panic(Err(CResult::InternalError), || build_session(builder, CSessionType::P2P)) {
+        Ok(h) => h,
+        Err(_) => INVALID_HANDLE
+    }
+}
+
+#[no_mangle]
+pub extern fn ggrs_builder_start_p2p_session() -> CSessionHandle{
+    ggrs_builder_start_p2p_session_ex(DEFAULT_BUILDER)
+}
+
+#[no_mangle]
+pub extern fn ggrs_builder_start_spectator_session_ex(builder: CBuilderHandle) -> CSessionHandle{
+    match catch_panic(Err(CResult::InternalError), || build_session(builder, CSessionType::Spectator)) {
+        Ok(h) => h,
+        Err(_) => INVALID_HANDLE
+    }
+}
+
+#[no_mangle]
+pub extern fn ggrs_builder_start_spectator_session() -> CSessionHandle{
+    ggrs_builder_start_spectator_session_ex(DEFAULT_BUILDER)
+}
+
+#[no_mangle]
+pub extern fn ggrs_session_poll_remote_clients(handle: CSessionHandle) -> CResult
+{
+    #[cfg(feature = "c_socket")]
+    socket::receive_transport(handle);
+    with_session(handle, CResult::InvalidHandle, |data| {
+        match &mut data.session {
+            CSession::SyncTest(_) => CResult::Ok,
+            CSession::P2P(p2p) => {
+                catch_panic(CResult::InternalError, || { p2p.poll_remote_clients(); CResult::Ok })
+            }
+            CSession::Spectator(spectator) => {
+                catch_panic(CResult::InternalError, || { spectator.poll_remote_clients(); CResult::Ok })
             }
-            None => return
         }
+    })
+}
 
-        match SESSIONS.get_mut(&handle) {
-            Some(sess) => {
-                match sess {
-                    CSession::SyncTest(_) => return,
-                    CSession::P2P(p2p) => {
-                        for event in p2p.events() {
-                            match event {
-                                GgrsEvent::Synchronizing{..} => c_events.push_back(CEvent::new_synchronizing()),
-                                GgrsEvent::Synchronized{..} => c_events.push_back(CEvent::new_synchronized()),
-                                GgrsEvent::Disconnected{..} => c_events.push_back(CEvent::new_disconnected()),
-                                GgrsEvent::NetworkInterrupted{..} => c_events.push_back(CEvent::new_network_interrupted()),
-                                GgrsEvent::NetworkResumed{..} => c_events.push_back(CEvent::new_network_resumed()),
-                                GgrsEvent::WaitRecommendation{skip_frames} => c_events.push_back(CEvent::new_wait_recommendation(&skip_frames)),
-                                GgrsEvent::DesyncDetected{..} => c_events.push_back(CEvent::new_desync_detected())
-                            }
-                        }
-                    }
-                    CSession::Spectator(spectator) => {
-                        for event in spectator.events() {
-                            match event {
-                                GgrsEvent::Synchronizing{..} => c_events.push_back(CEvent::new_synchronizing()),
-                                GgrsEvent::Synchronized{..} => c_events.push_back(CEvent::new_synchronized()),
-                                GgrsEvent::Disconnected{..} => c_events.push_back(CEvent::new_disconnected()),
-                                GgrsEvent::NetworkInterrupted{..} => c_events.push_back(CEvent::new_network_interrupted()),
-                                GgrsEvent::NetworkResumed{..} => c_events.push_back(CEvent::new_network_resumed()),
-                                GgrsEvent::WaitRecommendation{skip_frames} => c_events.push_back(CEvent::new_wait_recommendation(&skip_frames)),
-                                GgrsEvent::DesyncDetected{..} => c_events.push_back(CEvent::new_desync_detected())
-                            }
-                        }
+#[no_mangle]
+pub extern fn ggrs_session_current_state(handle: CSessionHandle) -> CSessionState
+{
+    with_session(handle, CSessionState::Running, |data| {
+        match &data.session {
+            CSession::SyncTest(_) => {
+                CSessionState::Running
+            }
+            CSession::P2P(p2p) => {
+                match p2p.current_state() {
+                    SessionState::Synchronizing => CSessionState::Synchronizing,
+                    SessionState::Running => CSessionState::Running
+                }
+            }
+            CSession::Spectator(spectator) => {
+                match spectator.current_state() {
+                    SessionState::Synchronizing => CSessionState::Synchronizing,
+                    SessionState::Running => CSessionState::Running
+                }
+            }
+        }
+    })
+}
 
-                    }
-                };
+#[no_mangle]
+pub extern fn ggrs_session_frames_ahead(handle: CSessionHandle) -> i32
+{
+    with_session(handle, 0, |data| {
+        match &data.session {
+            CSession::SyncTest(_) => {
+                0
+            }
+            CSession::P2P(p2p) => {
+                p2p.frames_ahead()
+            }
+            CSession::Spectator(_) => {
+                0
+            }
+        }
+    })
+}
+
+#[no_mangle]
+pub extern fn ggrs_session_current_frame(handle: CSessionHandle) -> CFrame
+{
+    with_session(handle, NULL_FRAME, |data| {
+        match &data.session {
+            CSession::P2P(p2p) => p2p.current_frame(),
+            _ => data.current_frame
+        }
+    })
+}
+
+#[no_mangle]
+pub extern fn ggrs_session_confirmed_frame(handle: CSessionHandle) -> CFrame
+{
+    with_session(handle, NULL_FRAME, |data| {
+        match &data.session {
+            CSession::SyncTest(st) => {
+                // SyncTest pretends every frame older than the check distance is confirmed
+                let frame = data.current_frame - st.check_distance() as CFrame;
+                frame.max(NULL_FRAME)
             }
-            None => return
+            CSession::P2P(p2p) => p2p.confirmed_frame(),
+            // Spectators only advance with confirmed inputs
+            CSession::Spectator(_) => data.current_frame
+        }
+    })
+}
+
+#[no_mangle]
+pub extern fn ggrs_session_frames_behind_host(handle: CSessionHandle) -> usize
+{
+    with_session(handle, 0, |data| {
+        match &data.session {
+            CSession::Spectator(spectator) => spectator.frames_behind_host(),
+            _ => 0
+        }
+    })
+}
+
+#[no_mangle]
+pub extern fn ggrs_session_network_stats(handle: CSessionHandle, player_handle: CPlayerHandle, stats: &mut CNetworkStats) -> CResult
+{
+    with_session(handle, CResult::InvalidHandle, |data| {
+        let result = match &data.session {
+            CSession::SyncTest(_) => return report(CResult::InvalidRequest, "SyncTest sessions have no network stats."),
+            CSession::P2P(p2p) => p2p.network_stats(player_handle),
+            // Spectators are only connected to their host
+            CSession::Spectator(spectator) => spectator.network_stats()
+        };
+
+        match result {
+            Ok(s) => {
+                *stats = CNetworkStats::from(s);
+                CResult::Ok
+            }
+            Err(e) => CResult::from(e)
+        }
+    })
+}
+
+#[no_mangle]
+pub extern fn ggrs_session_disconnect_player(handle: CSessionHandle, player_handle: CPlayerHandle) -> CResult
+{
+    with_session(handle, CResult::InvalidHandle, |data| {
+        match &mut data.session {
+            CSession::P2P(p2p) => {
+                match catch_panic(Err(CResult::InternalError), || p2p.disconnect_player(player_handle).map_err(CResult::from)) {
+                    Ok(_) => CResult::Ok,
+                    Err(code) => code
+                }
+            }
+            _ => report(CResult::InvalidRequest, "Only P2P sessions can disconnect players.")
+        }
+    })
+}
+
+#[no_mangle]
+pub extern fn ggrs_session_add_local_input(handle: CSessionHandle, player_handle: CPlayerHandle, input: CInput) -> CResult {
+    let input_size = with_session(handle, 0, |data| data.input_size);
+    let bytes = input.to_le_bytes();
+    unsafe { ggrs_session_add_local_input_bytes(handle, player_handle, bytes.as_ptr(), input_size.min(bytes.len())) }
+}
+
+/// # Safety
+/// `input` must point to at least `input_length` readable bytes.
+#[no_mangle]
+pub unsafe extern fn ggrs_session_add_local_input_bytes(handle: CSessionHandle, player_handle: CPlayerHandle, input: *const u8, input_length: usize) -> CResult {
+    let mut input_bytes: CInputBytes = [0; CINPUT_BUFFER_SIZE];
+
+    with_session(handle, CResult::InvalidHandle, |data| {
+        if input.is_null() && input_length > 0 {
+            return report(CResult::InvalidRequest, format!("Input of {} bytes is null.", input_length));
+        }
+        if input_length > data.input_size {
+            return report(CResult::InvalidRequest, format!("Input of {} bytes exceeds the session input size of {} bytes.", input_length, data.input_size));
+        }
+
+        if input_length > 0 {
+            input_bytes[..input_length].copy_from_slice(unsafe { std::slice::from_raw_parts(input, input_length) });
+        }
+
+        let result = match &mut data.session {
+            CSession::SyncTest(st) => {
+                catch_panic(Err(CResult::InternalError), || st.add_local_input(player_handle, input_bytes).map_err(CResult::from))
+            }
+            CSession::P2P(p2p) => {
+                catch_panic(Err(CResult::InternalError), || p2p.add_local_input(player_handle, input_bytes).map_err(CResult::from))
+            }
+            CSession::Spectator(_) => return report(CResult::InvalidRequest, "Spectator sessions do not accept local input.")
         };
+
+        match result {
+            Ok(_) => CResult::Ok,
+            Err(code) => code
+        }
+    })
+}
+
+fn advance_frame_error(data: &mut SessionData, err: &GgrsError) -> CResult {
+    if let GgrsError::MismatchedChecksum { current_frame, mismatched_frames } = err {
+        data.checked_frame = *current_frame;
+        data.mismatched_frames = mismatched_frames.clone();
     }
+    CResult::from(err)
 }
 
 #[no_mangle]
-pub extern fn ggrs_session_next_event(handle: CSessionHandle) -> CEvent {
-    unsafe {
-        match EVENTS.get_mut(&handle) {
-            Some(events) => {
-                match events.pop_front() {
-                    Some(r) => return r,
-                    None => return CEvent::new_none()
+pub extern fn ggrs_session_advance_frame(handle: CSessionHandle) -> CResult {
+    // GGRS polls the remote clients while advancing
+    #[cfg(feature = "c_socket")]
+    socket::receive_transport(handle);
+    let (result, callbacks, requests) = with_session(handle, (CResult::InvalidHandle, None, Vec::new()), |data| {
+        let result = catch_panic(CResult::InternalError, || advance_frame(data));
+        match data.callbacks {
+            Some(callbacks) => (result, Some(callbacks), data.requests.drain(..).collect()),
+            None => (result, None, Vec::new())
+        }
+    });
+
+    if let Some(callbacks) = callbacks {
+        run_callbacks(handle, &callbacks, requests);
+    }
+    result
+}
+
+fn run_callbacks(handle: CSessionHandle, callbacks: &CCallbacks, requests: Vec<CRequest>) {
+    let mut inputs: Vec<CRequest> = Vec::new();
+
+    for req in requests {
+        match req.request_type {
+            CRequestType::SaveGameState => (callbacks.save)(callbacks.user_data, handle, req.frame),
+            CRequestType::LoadGameState => (callbacks.load)(callbacks.user_data, handle, req.frame),
+            CRequestType::SetInput => inputs.push(req),
+            CRequestType::AdvanceFrame => {
+                (callbacks.advance)(callbacks.user_data, handle, inputs.as_ptr(), inputs.len());
+                inputs.clear();
+            }
+            CRequestType::None => {}
+        }
+    }
+}
+
+fn advance_frame(data: &mut SessionData) -> CResult {
+    let max_prediction: CFrame;
+
+    // GGRS skips desync detection for cells without a checksum and panics once such a cell is reused
+    if data.checksums_required {
+        if let Some((frame, _)) = data.pending_saves.iter().find(|(_, save)| save.remaining > 0) {
+            return report(CResult::InvalidRequest, format!("Save of frame {} must be completed with a checksum before advancing.", frame));
+        }
+    }
+
+    // Get ggrs_requests
+    let result = match &mut data.session {
+        CSession::SyncTest(st) => {
+            max_prediction = st.max_prediction() as CFrame;
+            st.advance_frame()
+        }
+        CSession::P2P(p2p) => {
+            max_prediction = p2p.max_prediction() as CFrame;
+            p2p.advance_frame()
+        }
+        CSession::Spectator(spectator) => {
+            max_prediction = 0;
+            spectator.advance_frame()
+        }
+    };
+    let ggrs_requests = match result {
+        Ok(req) => req,
+        Err(e) => return advance_frame_error(data, &e)
+    };
+
+    // Convert requests to GgrsCppRequest's
+    for req in ggrs_requests {
+        match req {
+            GgrsRequest::SaveGameState{ frame, cell } => {
+                // Save right away so the frame is known to GGRS, the checksum follows in ggrs_session_complete_save
+                cell.save(frame, None, None);
+                // GGRS reuses cells in a ring of max_prediction, older frames may already point to another frame
+                data.pending_saves.retain(|f, _| *f > frame - max_prediction);
+                let remaining = data.pending_saves.get(&frame).map_or(0, |save| save.remaining) + 1;
+                data.pending_saves.insert(frame, PendingSave { cell, remaining });
+                data.requests.push_back(CRequest::new_save(frame));
+            }
+
+            GgrsRequest::LoadGameState { frame, cell } => {
+                // Copy the state out now, later save requests in this batch may overwrite the same cell
+                data.loaded_states.retain(|f, _| *f > frame - max_prediction);
+                data.loaded_states.insert(frame, cell.load().unwrap_or_default());
+                data.current_frame = frame;
+                data.requests.push_back(CRequest::new_load(frame));
+            }
+
+            GgrsRequest::AdvanceFrame { inputs } => {
+                for i in 0..inputs.len() {
+                    data.requests.push_back(CRequest::new_input(i, inputs[i].0, data.input_size, inputs[i].1));
+                }
+                
+                data.current_frame += 1;
+                data.requests.push_back(CRequest::new_advance(inputs.len()));
+            }
+        }
+
+    }
+
+    CResult::Ok
+}
+
+/// Completes the save of frame, fails with InvalidRequest if there is no pending save for frame or it was already completed.
+#[no_mangle]
+pub extern fn ggrs_session_complete_save(handle: CSessionHandle, frame: CFrame, checksum: CChecksum) -> CResult {
+    unsafe { ggrs_session_complete_save_with_state(handle, frame, std::ptr::null(), 0, checksum) }
+}
+
+/// # Safety
+/// `state` must be null or point to at least `state_length` readable bytes.
+///
+/// Completes the save of frame like ggrs_session_complete_save and stores a copy of state with it.
+#[no_mangle]
+pub unsafe extern fn ggrs_session_complete_save_with_state(handle: CSessionHandle, frame: CFrame, state: *const u8, state_length: usize, checksum: CChecksum) -> CResult {
+    with_session(handle, CResult::InvalidHandle, |data| {
+        match data.pending_saves.get_mut(&frame) {
+            Some(save) if save.remaining == 0 => report(CResult::InvalidRequest, format!("Save of frame {} was already completed.", frame)),
+            Some(save) => {
+                let state = if state.is_null() || state_length == 0 {
+                    None
+                } else {
+                    Some(unsafe { std::slice::from_raw_parts(state, state_length) }.to_vec())
                 };
+                save.cell.save(frame, state, Some(checksum as u128));
+                save.remaining -= 1;
+                CResult::Ok
+            }
+            None => report(CResult::InvalidRequest, format!("No pending save for frame {}.", frame))
+        }
+    })
+}
+
+/// # Safety
+/// `buffer` must be null or point to at least `buffer_length` writable bytes.
+///
+/// Returns the length of the state saved for frame, the state is only copied if buffer_length is large enough.
+#[no_mangle]
+pub unsafe extern fn ggrs_session_load_game_state(handle: CSessionHandle, frame: CFrame, buffer: *mut u8, buffer_length: usize) -> usize {
+    with_session(handle, 0, |data| {
+        match data.loaded_states.get(&frame) {
+            Some(state) => {
+                if !buffer.is_null() && buffer_length >= state.len() {
+                    unsafe { std::ptr::copy_nonoverlapping(state.as_ptr(), buffer, state.len()) };
+                }
+                state.len()
+            }
+            None => {
+                report(CResult::InvalidRequest, format!("No state was loaded for frame {}.", frame));
+                0
+            }
+        }
+    })
+}
+
+/// Passes the requests of every following ggrs_session_advance_frame to the callbacks instead of queueing them.
+/// Save callbacks complete the save with ggrs_session_complete_save, load callbacks read the state with ggrs_session_load_game_state.
+/// Advance callbacks receive the SetInput requests of all players. Passing null for all callbacks restores polling.
+#[no_mangle]
+pub extern fn ggrs_session_set_callbacks(handle: CSessionHandle, save: CSaveCallback, load: CLoadCallback, advance: CAdvanceCallback, user_data: *mut c_void) -> CResult {
+    let callbacks = match (save, load, advance) {
+        (Some(save), Some(load), Some(advance)) => Some(CCallbacks { save, load, advance, user_data }),
+        (None, None, None) => None,
+        _ => return report(CResult::InvalidRequest, "Save, load and advance callbacks must be set together.")
+    };
+
+    with_session(handle, CResult::InvalidHandle, |data| {
+        data.callbacks = callbacks;
+        CResult::Ok
+    })
+}
+
+/// Returns the frame a SyncTest session was at when it returned its last MismatchedChecksum,
+/// the frames that mismatched are returned by ggrs_session_mismatched_frames.
+#[no_mangle]
+pub extern fn ggrs_session_mismatched_frame(handle: CSessionHandle) -> CFrame {
+    with_session(handle, NULL_FRAME, |data| data.checked_frame)
+}
+
+/// # Safety
+/// `frames` must be null or point to at least `frames_length` writable frames.
+///
+/// Returns how many frames mismatched in the last MismatchedChecksum, the frames are only copied if frames_length is large enough.
+#[no_mangle]
+pub unsafe extern fn ggrs_session_mismatched_frames(handle: CSessionHandle, frames: *mut CFrame, frames_length: usize) -> usize {
+    with_session(handle, 0, |data| {
+        let mismatched = &data.mismatched_frames;
+        if !frames.is_null() && frames_length >= mismatched.len() {
+            unsafe { std::ptr::copy_nonoverlapping(mismatched.as_ptr(), frames, mismatched.len()) };
+        }
+        mismatched.len()
+    })
+}
+
+#[no_mangle]
+pub extern fn ggrs_session_next_ggrsRequest(handle: CSessionHandle) -> CRequest {
+    with_session(handle, CRequest::new_none(), |data| {
+        match data.requests.pop_front() {
+            Some(r) => r,
+            None => CRequest::new_none()
+        }
+    })
+}
+
+/// # Safety
+/// `requests` must point to at least `max_requests` writable requests, `inputs` must be null or point to at least `max_inputs`.
+///
+/// Moves queued requests into requests and returns how many were written, more is set when requests remain queued.
+/// With inputs set, SetInput requests are written to inputs instead. Each AdvanceFrame then carries the inputs of all players
+/// as the num_inputs entries of inputs starting at its inputs_offset.
+/// BufferTooSmall is reported when the inputs of the next AdvanceFrame do not fit in max_inputs, nothing is written then.
+#[no_mangle]
+pub unsafe extern fn ggrs_session_next_requests(handle: CSessionHandle, requests: *mut CRequest, max_requests: usize, inputs: *mut CRequest, max_inputs: usize, more: &mut bool) -> usize {
+    with_session(handle, 0, |data| {
+        let mut num_requests: usize = 0;
+        let mut num_inputs: usize = 0;
+
+        while num_requests < max_requests && !requests.is_null() {
+            let group = match data.requests.front() {
+                Some(r) => match r.request_type {
+                    CRequestType::SetInput if !inputs.is_null() => data.requests.iter().take_while(|r| matches!(r.request_type, CRequestType::SetInput)).count(),
+                    _ => 0
+                },
+                None => break
+            };
+
+            // Inputs are only handed out together with their AdvanceFrame
+            let inputs_offset = num_inputs;
+            if group > 0 {
+                if num_inputs + group > max_inputs {
+                    if num_requests == 0 {
+                        report(CResult::BufferTooSmall, format!("AdvanceFrame with {} inputs does not fit in {} inputs.", group, max_inputs));
+                    }
+                    break;
+                }
+                for req in data.requests.drain(..group) {
+                    unsafe { inputs.add(num_inputs).write(req) };
+                    num_inputs += 1;
+                }
+            }
+
+            if let Some(mut req) = data.requests.pop_front() {
+                if group > 0 {
+                    req.inputs_offset = inputs_offset as u32;
+                }
+                unsafe { requests.add(num_requests).write(req) };
+                num_requests += 1;
+            }
+        }
+
+        *more = !data.requests.is_empty();
+        num_requests
+    })
+}
+
+#[no_mangle]
+pub extern fn ggrs_session_process_events(handle: CSessionHandle) -> CResult {
+    with_session(handle, CResult::InvalidHandle, |data| {
+        match &mut data.session {
+            CSession::SyncTest(_) => return CResult::Ok,
+            CSession::P2P(p2p) => {
+                for event in p2p.events() {
+                    data.events.push_back(CEvent::from(event));
+                }
+            }
+            CSession::Spectator(spectator) => {
+                for event in spectator.events() {
+                    data.events.push_back(CEvent::from(event));
+                }
             }
-            None => return CEvent::new_none()
         };
-    }
+
+        CResult::Ok
+    })
+}
+
+#[no_mangle]
+pub extern fn ggrs_session_next_event(handle: CSessionHandle) -> CEvent {
+    with_session(handle, CEvent::new_none(), |data| {
+        match data.events.pop_front() {
+            Some(r) => r,
+            None => CEvent::new_none()
+        }
+    })
 }
 
 #[no_mangle]
 pub extern fn ggrs_session_close(handle: CSessionHandle) {
-    unsafe{
-        SESSIONS.remove(&handle);
-        REQUESTS.remove(&handle);
-        EVENTS.remove(&handle);
-        #[cfg(feature = "c_socket")]
-        socket::SOCKET_IN.remove(&handle);
-        #[cfg(feature = "c_socket")]
-        socket::SOCKET_OUT.remove(&handle);
+    lock(&context().sessions).remove(&handle);
+    #[cfg(feature = "c_socket")]
+    socket::close(handle);
+}
+
+///////////////////////
+// Context Functions //
+///////////////////////
+#[no_mangle]
+pub extern fn ggrs_context_new() -> *mut CContext {
+    Arc::into_raw(Arc::new(CContext::new())) as *mut CContext
+}
+
+/// # Safety
+/// `ctx` must be null or returned by ggrs_context_new and not freed before.
+///
+/// Makes ctx the context used by all other functions called from this thread, null restores the default context.
+/// A context may be current on several threads at once, e.g. the game thread and a network thread.
+#[no_mangle]
+pub unsafe extern fn ggrs_context_make_current(ctx: *mut CContext) {
+    let ctx = if ctx.is_null() {
+        None
+    } else {
+        unsafe {
+            Arc::increment_strong_count(ctx);
+            Some(Arc::from_raw(ctx as *const CContext))
+        }
+    };
+    CURRENT_CONTEXT.with(|c| *c.borrow_mut() = ctx);
+}
+
+/// # Safety
+/// `ctx` must be null or returned by ggrs_context_new and not freed before.
+///
+/// Closes all sessions and builders of ctx. Threads that still have ctx current get InvalidHandle for them,
+/// the context itself is released once no thread has it current anymore.
+#[no_mangle]
+pub unsafe extern fn ggrs_context_free(ctx: *mut CContext) {
+    if ctx.is_null() {
+        return;
+    }
+    let ctx = unsafe { Arc::from_raw(ctx as *const CContext) };
+    lock(&ctx.sessions).clear();
+    lock(&ctx.builders).clear();
+#[cfg(feature = "c_socket")]
+    lock(&ctx.sockets).clear();
+
+    CURRENT_CONTEXT.with(|c| {
+        let mut current = c.borrow_mut();
+        if current.as_ref().is_some_and(|current| Arc::ptr_eq(current, &ctx)) {
+            *current = None;
+        }
+    });
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::error::ggrs_last_error_code;
+
+    // Tests run on threads of their own, a context per test keeps their sessions and builders apart
+    pub(crate) struct TestContext(*mut CContext);
+    impl TestContext {
+        pub(crate) fn new() -> Self {
+            let ctx = ggrs_context_new();
+            unsafe { ggrs_context_make_current(ctx) };
+            Self(ctx)
+        }
+    }
+    impl Drop for TestContext {
+        fn drop(&mut self) {
+            unsafe { ggrs_context_free(self.0) };
+        }
+    }
+
+    pub(crate) fn drain_requests(handle: CSessionHandle) -> Vec<CRequest> {
+        let mut requests = Vec::new();
+        loop {
+            let req = ggrs_session_next_ggrsRequest(handle);
+            if matches!(req.request_type, CRequestType::None) {
+                return requests;
+            }
+            requests.push(req);
+        }
+    }
+
+    #[cfg(feature = "c_socket")]
+    pub(crate) struct Peer {
+        pub(crate) handle: CSessionHandle,
+        pub(crate) addr: socket::CAddressHandle,
+        pub(crate) player: CPlayerHandle
+    }
+
+    // Two P2P players that know each other by opaque addresses, their messages are exchanged in memory
+    #[cfg(feature = "c_socket")]
+    pub(crate) fn start_pair(configure: impl Fn(CBuilderHandle)) -> [Peer; 2] {
+        let addrs = [b"player 0", b"player 1"].map(|id| unsafe { address::ggrs_address_register_bytes(id.as_ptr(), id.len()) });
+        [0, 1].map(|player| {
+            let builder = ggrs_builder_create();
+            assert_eq!(ggrs_builder_add_local_player_ex(builder, player), CResult::Ok);
+            assert_eq!(ggrs_builder_add_remote_player_ex(builder, 1 - player, addrs[1 - player]), CResult::Ok);
+            configure(builder);
+            let handle = ggrs_builder_start_p2p_session_ex(builder);
+            ggrs_builder_free(builder);
+            assert_ne!(handle, INVALID_HANDLE);
+            Peer { handle, addr: addrs[player], player }
+        })
+    }
+
+    #[cfg(feature = "c_socket")]
+    pub(crate) fn exchange(peers: &[Peer; 2]) {
+        let mut buffer = [0u8; 1024];
+        for (from, to) in [(&peers[0], &peers[1]), (&peers[1], &peers[0])] {
+            let mut addr = 0;
+            loop {
+                let length = unsafe { socket::ggrs_socket_out_message_bytes(from.handle, &mut addr, buffer.as_mut_ptr(), buffer.len()) };
+                if length == 0 {
+                    break;
+                }
+                assert_eq!(addr, to.addr);
+                assert_eq!(unsafe { socket::ggrs_socket_in_message_bytes(to.handle, from.addr, buffer.as_ptr(), length) }, CResult::Ok);
+            }
+        }
+    }
+
+    // Advances both players up to frame, completing every save with the checksum from save. Returns all events.
+    #[cfg(feature = "c_socket")]
+    pub(crate) fn run_pair(peers: &[Peer; 2], frame: CFrame, mut save: impl FnMut(&Peer, CFrame) -> CChecksum) -> Vec<(CPlayerHandle, CEvent)> {
+        let mut events = Vec::new();
+        for _ in 0..5000 {
+            if peers.iter().all(|p| ggrs_session_current_frame(p.handle) >= frame) {
+                return events;
+            }
+            for peer in peers {
+                assert_eq!(ggrs_session_poll_remote_clients(peer.handle), CResult::Ok);
+            }
+            exchange(peers);
+
+            for peer in peers {
+                assert_eq!(ggrs_session_process_events(peer.handle), CResult::Ok);
+                loop {
+                    let event = ggrs_session_next_event(peer.handle);
+                    if matches!(event.event_type, CEventTypes::None) {
+                        break;
+                    }
+                    events.push((peer.player, event));
+                }
+                if !matches!(ggrs_session_current_state(peer.handle), CSessionState::Running) || ggrs_session_current_frame(peer.handle) >= frame {
+                    continue;
+                }
+
+                assert_eq!(ggrs_session_add_local_input(peer.handle, peer.player, 1), CResult::Ok);
+                match ggrs_session_advance_frame(peer.handle) {
+                    CResult::Ok => {}
+                    CResult::PredictionThreshold => continue,
+                    result => panic!("Advancing player {} failed with {:?}", peer.player, result)
+                }
+                for req in drain_requests(peer.handle) {
+                    if matches!(req.request_type, CRequestType::SaveGameState) {
+                        let checksum = save(peer, req.frame);
+                        assert_eq!(ggrs_session_complete_save(peer.handle, req.frame, checksum), CResult::Ok);
+                    }
+                }
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        panic!("Players did not reach frame {}", frame);
+    }
+
+    #[test]
+    fn desync_detection_rejects_sparse_saving() {
+        let _ctx = TestContext::new();
+        let builder = ggrs_builder_create();
+        assert_eq!(ggrs_builder_with_sparse_saving_mode_ex(builder, true), CResult::Ok);
+        assert_eq!(ggrs_builder_with_desync_detection_mode_ex(builder, 10), CResult::Ok);
+        assert_eq!(ggrs_builder_start_p2p_session_ex(builder), INVALID_HANDLE);
+        assert_eq!(ggrs_last_error_code(), CResult::InvalidRequest);
+    }
+
+    #[cfg(feature = "c_socket")]
+    #[test]
+    fn desync_detection_requires_completed_saves() {
+        let _ctx = TestContext::new();
+        let peers = start_pair(|b| { ggrs_builder_with_desync_detection_mode_ex(b, 10); });
+        run_pair(&peers, 5, |_, frame| frame as CChecksum);
+
+        let handle = peers[0].handle;
+        assert_eq!(ggrs_session_add_local_input(handle, 0, 1), CResult::Ok);
+        assert_eq!(ggrs_session_advance_frame(handle), CResult::Ok);
+        let save = drain_requests(handle).into_iter().find(|r| matches!(r.request_type, CRequestType::SaveGameState)).expect("No save requested");
+
+        assert_eq!(ggrs_session_add_local_input(handle, 0, 1), CResult::Ok);
+        assert_eq!(ggrs_session_advance_frame(handle), CResult::InvalidRequest);
+        assert_eq!(ggrs_session_complete_save(handle, save.frame, save.frame as CChecksum), CResult::Ok);
+        assert_eq!(ggrs_session_advance_frame(handle), CResult::Ok);
+    }
+
+    #[cfg(feature = "c_socket")]
+    #[test]
+    fn desync_detection_accepts_matching_checksums() {
+        let _ctx = TestContext::new();
+        let peers = start_pair(|b| { ggrs_builder_with_desync_detection_mode_ex(b, 10); });
+        let events = run_pair(&peers, 60, |_, frame| frame as CChecksum);
+        assert!(!events.iter().any(|(_, e)| matches!(e.event_type, CEventTypes::DesyncDetected)));
+    }
+
+    #[cfg(feature = "c_socket")]
+    #[test]
+    fn desync_detection_reports_mismatched_checksums() {
+        let _ctx = TestContext::new();
+        let peers = start_pair(|b| { ggrs_builder_with_desync_detection_mode_ex(b, 10); });
+        let events = run_pair(&peers, 80, |peer, frame| if peer.player == 1 && frame >= 30 { frame as CChecksum + 1 } else { frame as CChecksum });
+
+        let (player, desync) = events.iter().find(|(_, e)| matches!(e.event_type, CEventTypes::DesyncDetected)).expect("No desync detected");
+        let desync = unsafe { desync.data.desync_detected };
+        assert!(desync.frame >= 30 && desync.frame % 10 == 0);
+        assert_ne!(desync.local_checksum, desync.remote_checksum);
+        assert_eq!(desync.addr, peers[1 - player].addr);
+    }
+
+    // Returns the distinct frames saved by the first player
+    #[cfg(feature = "c_socket")]
+    fn saved_frames(sparse_saving: bool) -> Vec<CFrame> {
+        let _ctx = TestContext::new();
+        let peers = start_pair(|b| { ggrs_builder_with_sparse_saving_mode_ex(b, sparse_saving); });
+        let mut frames = Vec::new();
+        run_pair(&peers, 60, |peer, frame| {
+            if sparse_saving {
+                // The first frame is always saved, before anything is confirmed
+                assert!(frame == 0 || frame <= ggrs_session_confirmed_frame(peer.handle), "Frame {} saved before it was confirmed", frame);
+            }
+            if peer.player == 0 {
+                frames.push(frame);
+            }
+            frame as CChecksum
+        });
+        frames.sort();
+        frames.dedup();
+        frames
+    }
+
+    #[cfg(feature = "c_socket")]
+    #[test]
+    fn sparse_saving_skips_frames() {
+        assert_eq!(saved_frames(false), (0..60).collect::<Vec<_>>());
+        let sparse = saved_frames(true);
+        assert!(!sparse.is_empty() && sparse.len() < 60 / 2, "Saved frames {:?}", sparse);
+    }
+
+    #[cfg(feature = "c_socket")]
+    #[test]
+    fn synchronizing_events_carry_their_payload() {
+        let _ctx = TestContext::new();
+        let peers = start_pair(|_| {});
+        let events = run_pair(&peers, 1, |_, frame| frame as CChecksum);
+
+        for peer in &peers {
+            let remote = peers[1 - peer.player].addr;
+            let synchronizing: Vec<CSynchronizingEvent> = events.iter()
+                .filter(|(p, e)| *p == peer.player && matches!(e.event_type, CEventTypes::Synchronizing))
+                .map(|(_, e)| unsafe { e.data.synchronizing })
+                .collect();
+            assert!(!synchronizing.is_empty());
+            assert!(synchronizing.iter().all(|s| s.addr == remote && s.count < s.total));
+
+            let synchronized = events.iter().find(|(p, e)| *p == peer.player && matches!(e.event_type, CEventTypes::Synchronized)).expect("Not synchronized");
+            assert_eq!(unsafe { synchronized.1.data.synchronized.addr }, remote);
+        }
+    }
+
+    fn start_synctest(builder: CBuilderHandle, num_players: usize) -> CSessionHandle {
+        assert_eq!(ggrs_builder_with_num_players_ex(builder, num_players), CResult::Ok);
+        assert_eq!(ggrs_builder_with_input_delay_ex(builder, 0), CResult::Ok);
+        for player in 0..num_players {
+            assert_eq!(ggrs_builder_add_local_player_ex(builder, player), CResult::Ok);
+        }
+        let handle = ggrs_builder_start_synctest_session_ex(builder);
+        assert_ne!(handle, INVALID_HANDLE);
+        handle
+    }
+
+    fn advance_synctest(handle: CSessionHandle, num_players: usize) {
+        for player in 0..num_players {
+            assert_eq!(ggrs_session_add_local_input(handle, player, player as CInput + 10), CResult::Ok);
+        }
+        assert_eq!(ggrs_session_advance_frame(handle), CResult::Ok);
+    }
+
+    fn next_requests(handle: CSessionHandle, max_requests: usize, max_inputs: Option<usize>) -> (Vec<CRequest>, Vec<CRequest>, bool) {
+        let mut requests: Vec<CRequest> = (0..max_requests).map(|_| CRequest::new_none()).collect();
+        let mut inputs: Vec<CRequest> = (0..max_inputs.unwrap_or(0)).map(|_| CRequest::new_none()).collect();
+        let inputs_ptr = if max_inputs.is_some() { inputs.as_mut_ptr() } else { std::ptr::null_mut() };
+        let mut more = false;
+        let num_requests = unsafe { ggrs_session_next_requests(handle, requests.as_mut_ptr(), max_requests, inputs_ptr, inputs.len(), &mut more) };
+        requests.truncate(num_requests);
+        let num_inputs = requests.iter().map(|r| r.num_inputs as usize).sum();
+        inputs.truncate(num_inputs);
+        (requests, inputs, more)
+    }
+
+    fn complete_saves(handle: CSessionHandle, requests: &[CRequest]) {
+        for req in requests.iter().filter(|r| matches!(r.request_type, CRequestType::SaveGameState)) {
+            assert_eq!(ggrs_session_complete_save(handle, req.frame, req.frame as CChecksum), CResult::Ok);
+        }
+    }
+
+    #[test]
+    fn next_requests_hands_out_inputs_with_their_advance() {
+        let _ctx = TestContext::new();
+        let handle = start_synctest(ggrs_builder_create(), 2);
+        for _ in 0..5 {
+            advance_synctest(handle, 2);
+            let (requests, inputs, more) = next_requests(handle, 16, Some(16));
+            assert!(!more);
+            assert!(!requests.iter().any(|r| matches!(r.request_type, CRequestType::SetInput)));
+            let mut next_offset = 0;
+            for advance in requests.iter().filter(|r| matches!(r.request_type, CRequestType::AdvanceFrame)) {
+                assert_eq!(advance.num_inputs, 2);
+                assert_eq!(advance.inputs_offset as usize, next_offset);
+                let offset = advance.inputs_offset as usize;
+                for (player, input) in inputs[offset..offset + 2].iter().enumerate() {
+                    assert!(matches!(input.request_type, CRequestType::SetInput));
+                    assert_eq!(input.player_handle, player);
+                    assert_eq!(input.input, player as CInput + 10);
+                }
+                next_offset = offset + 2;
+            }
+            assert_eq!(next_offset, inputs.len());
+            complete_saves(handle, &requests);
+        }
+    }
+
+    #[test]
+    fn next_requests_without_inputs_keeps_them_in_order() {
+        let _ctx = TestContext::new();
+        let handle = start_synctest(ggrs_builder_create(), 2);
+        advance_synctest(handle, 2);
+        let (requests, _, more) = next_requests(handle, 16, None);
+        assert!(!more);
+        let types: Vec<bool> = requests.iter().filter(|r| !matches!(r.request_type, CRequestType::SaveGameState)).map(|r| matches!(r.request_type, CRequestType::SetInput)).collect();
+        assert_eq!(types, [true, true, false]);
+    }
+
+    #[test]
+    fn next_requests_stops_before_inputs_that_do_not_fit() {
+        let _ctx = TestContext::new();
+        let handle = start_synctest(ggrs_builder_create(), 2);
+        // Once past the check distance every advance rolls back and simulates several frames
+        for _ in 0..4 {
+            advance_synctest(handle, 2);
+            let (requests, _, _) = next_requests(handle, 16, Some(16));
+            complete_saves(handle, &requests);
+        }
+        advance_synctest(handle, 2);
+
+        let mut advances = 0;
+        loop {
+            let (requests, inputs, more) = next_requests(handle, 16, Some(3));
+            assert_eq!(ggrs_last_error_code(), CResult::Ok);
+            assert!(requests.iter().filter(|r| matches!(r.request_type, CRequestType::AdvanceFrame)).count() <= 1);
+            assert!(inputs.len() <= 2);
+            advances += requests.iter().filter(|r| matches!(r.request_type, CRequestType::AdvanceFrame)).count();
+            complete_saves(handle, &requests);
+            if !more {
+                break;
+            }
+        }
+        assert!(advances > 1);
+    }
+
+    #[test]
+    fn next_requests_reports_too_few_inputs() {
+        let _ctx = TestContext::new();
+        let handle = start_synctest(ggrs_builder_create(), 2);
+        advance_synctest(handle, 2);
+        // Skip the save of the first frame
+        let (requests, _, _) = next_requests(handle, 1, Some(2));
+        complete_saves(handle, &requests);
+
+        let (requests, inputs, more) = next_requests(handle, 16, Some(1));
+        assert!(requests.is_empty() && inputs.is_empty() && more);
+        assert_eq!(ggrs_last_error_code(), CResult::BufferTooSmall);
+
+        let (requests, inputs, more) = next_requests(handle, 16, Some(2));
+        assert!(!more);
+        assert_eq!(inputs.len(), 2);
+        complete_saves(handle, &requests);
+    }
+
+    #[test]
+    fn complete_save_rejects_unknown_and_completed_saves() {
+        let _ctx = TestContext::new();
+        let handle = start_synctest(ggrs_builder_create(), 1);
+        advance_synctest(handle, 1);
+        let saves: Vec<CFrame> = drain_requests(handle).iter().filter(|r| matches!(r.request_type, CRequestType::SaveGameState)).map(|r| r.frame).collect();
+        assert_eq!(saves, [0]);
+
+        assert_eq!(ggrs_session_complete_save(handle, 0, 1), CResult::Ok);
+        assert_eq!(ggrs_session_complete_save(handle, 0, 1), CResult::InvalidRequest);
+        assert_eq!(ggrs_session_complete_save(handle, 5, 1), CResult::InvalidRequest);
+        assert_eq!(ggrs_session_complete_save(INVALID_HANDLE, 0, 1), CResult::InvalidHandle);
+        assert_eq!(ggrs_last_error_code(), CResult::InvalidHandle);
+    }
+
+    #[test]
+    fn complete_save_with_state_is_loaded_again() {
+        let _ctx = TestContext::new();
+        let handle = start_synctest(ggrs_builder_create(), 1);
+        let mut loads = 0;
+        for _ in 0..6 {
+            advance_synctest(handle, 1);
+            for req in drain_requests(handle) {
+                let state = req.frame.to_le_bytes();
+                match req.request_type {
+                    CRequestType::SaveGameState => {
+                        let result = unsafe { ggrs_session_complete_save_with_state(handle, req.frame, state.as_ptr(), state.len(), req.frame as CChecksum) };
+                        assert_eq!(result, CResult::Ok);
+                    }
+                    CRequestType::LoadGameState => {
+                        let mut buffer = [0u8; 8];
+                        assert_eq!(unsafe { ggrs_session_load_game_state(handle, req.frame, buffer.as_mut_ptr(), buffer.len()) }, state.len());
+                        assert_eq!(buffer[..state.len()], state);
+                        loads += 1;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        assert!(loads > 0);
+    }
+
+    #[test]
+    fn contexts_keep_their_sessions_apart() {
+        let ctx = TestContext::new();
+        let handle = start_synctest(ggrs_builder_create(), 1);
+        {
+            let _other = TestContext::new();
+            assert_eq!(ggrs_session_current_frame(handle), NULL_FRAME);
+            assert_eq!(ggrs_last_error_code(), CResult::InvalidHandle);
+        }
+        // Freeing the other context restored the default one
+        assert_eq!(ggrs_session_current_frame(handle), NULL_FRAME);
+        unsafe { ggrs_context_make_current(ctx.0) };
+        assert_eq!(ggrs_session_current_frame(handle), 0);
+    }
+
+    #[cfg(feature = "c_socket")]
+    #[test]
+    fn context_is_current_on_a_network_thread() {
+        let ctx = TestContext::new();
+        let peers = start_pair(|_| {});
+        let shared = ctx.0 as usize;
+        let running = std::sync::atomic::AtomicBool::new(true);
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                unsafe { ggrs_context_make_current(shared as *mut CContext) };
+                while running.load(Ordering::Relaxed) {
+                    exchange(&peers);
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                unsafe { ggrs_context_make_current(std::ptr::null_mut()) };
+            });
+
+            for _ in 0..2000 {
+                if peers.iter().all(|p| matches!(ggrs_session_current_state(p.handle), CSessionState::Running)) {
+                    break;
+                }
+                for peer in &peers {
+                    assert_eq!(ggrs_session_poll_remote_clients(peer.handle), CResult::Ok);
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            running.store(false, Ordering::Relaxed);
+        });
+        assert!(peers.iter().all(|p| matches!(ggrs_session_current_state(p.handle), CSessionState::Running)));
+    }
+
+    #[test]
+    fn freed_context_stays_usable_where_it_is_current() {
+        let ctx = ggrs_context_new();
+        unsafe { ggrs_context_make_current(ctx) };
+        let handle = start_synctest(ggrs_builder_create(), 1);
+        let shared = ctx as usize;
+        let (to_thread, from_main) = std::sync::mpsc::channel::<()>();
+        let (to_main, from_thread) = std::sync::mpsc::channel::<()>();
+
+        let thread = std::thread::spawn(move || {
+            unsafe { ggrs_context_make_current(shared as *mut CContext) };
+            assert_eq!(ggrs_session_current_frame(handle), 0);
+            to_main.send(()).unwrap();
+            from_main.recv().unwrap();
+
+            // The sessions are closed, the context itself is still alive
+            assert_eq!(ggrs_session_current_frame(handle), NULL_FRAME);
+            let builder = ggrs_builder_create();
+            assert_ne!(builder, DEFAULT_BUILDER);
+            ggrs_builder_free(builder);
+            unsafe { ggrs_context_make_current(std::ptr::null_mut()) };
+        });
+
+        from_thread.recv().unwrap();
+        unsafe { ggrs_context_free(ctx) };
+        assert_eq!(ggrs_session_current_frame(handle), NULL_FRAME);
+        to_thread.send(()).unwrap();
+        thread.join().unwrap();
+    }
+
+    #[cfg(feature = "c_socket")]
+    #[test]
+    fn failed_start_leaves_no_socket() {
+        let _ctx = TestContext::new();
+        let builder = ggrs_builder_create();
+        // Two players without the remote one
+        assert_eq!(ggrs_builder_add_local_player_ex(builder, 0), CResult::Ok);
+        assert_eq!(ggrs_builder_start_p2p_session_ex(builder), INVALID_HANDLE);
+        assert_eq!(ggrs_last_error_code(), CResult::InvalidRequest);
+        assert!(lock(&context().sockets).is_empty());
+    }
+
+    #[test]
+    fn spectator_sessions_require_a_host() {
+        let _ctx = TestContext::new();
+        let builder = ggrs_builder_create();
+        assert_eq!(ggrs_builder_start_spectator_session_ex(builder), INVALID_HANDLE);
+        assert_eq!(ggrs_last_error_code(), CResult::InvalidRequest);
+    }
+
+    #[test]
+    fn input_size_must_fit_the_input_buffer() {
+        let _ctx = TestContext::new();
+        for size in [0, CINPUT_BUFFER_SIZE + 1] {
+            let builder = ggrs_builder_create();
+            assert_eq!(ggrs_builder_with_input_size_ex(builder, size), CResult::Ok);
+            assert_eq!(ggrs_builder_add_local_player_ex(builder, 0), CResult::Ok);
+            assert_eq!(ggrs_builder_start_synctest_session_ex(builder), INVALID_HANDLE);
+            assert_eq!(ggrs_last_error_code(), CResult::InvalidRequest);
+        }
+
+        let builder = ggrs_builder_create();
+        assert_eq!(ggrs_builder_with_input_size_ex(builder, CINPUT_BUFFER_SIZE), CResult::Ok);
+        let handle = start_synctest(builder, 1);
+        let input = [1u8; CINPUT_BUFFER_SIZE + 1];
+        assert_eq!(unsafe { ggrs_session_add_local_input_bytes(handle, 0, input.as_ptr(), input.len()) }, CResult::InvalidRequest);
+        assert_eq!(unsafe { ggrs_session_add_local_input_bytes(handle, 0, std::ptr::null(), 1) }, CResult::InvalidRequest);
+        assert_eq!(unsafe { ggrs_session_add_local_input_bytes(handle, 0, input.as_ptr(), CINPUT_BUFFER_SIZE) }, CResult::Ok);
+    }
+
+    #[test]
+    fn synctest_reports_the_mismatched_frames() {
+        let _ctx = TestContext::new();
+        let handle = start_synctest(ggrs_builder_create(), 1);
+        assert_eq!(unsafe { ggrs_session_mismatched_frames(handle, std::ptr::null_mut(), 0) }, 0);
+
+        // Every save gets a new checksum, so resimulated frames never match
+        let mut checksum = 0;
+        let mut result = CResult::Ok;
+        for _ in 0..10 {
+            assert_eq!(ggrs_session_add_local_input(handle, 0, 1), CResult::Ok);
+            result = ggrs_session_advance_frame(handle);
+            if result != CResult::Ok {
+                break;
+            }
+            for req in drain_requests(handle) {
+                if matches!(req.request_type, CRequestType::SaveGameState) {
+                    checksum += 1;
+                    assert_eq!(ggrs_session_complete_save(handle, req.frame, checksum), CResult::Ok);
+                }
+            }
+        }
+        assert_eq!(result, CResult::MismatchedChecksum);
+
+        let checked = ggrs_session_mismatched_frame(handle);
+        let length = unsafe { ggrs_session_mismatched_frames(handle, std::ptr::null_mut(), 0) };
+        assert!(length > 0);
+        let mut frames = vec![NULL_FRAME; length];
+        assert_eq!(unsafe { ggrs_session_mismatched_frames(handle, frames.as_mut_ptr(), frames.len()) }, length);
+        assert!(frames.iter().all(|f| *f >= 0 && *f < checked), "{:?} checked at {}", frames, checked);
+    }
+
+    // Game driven by the session callbacks, its state is the sum of all inputs so far
+    #[derive(Default)]
+    struct CallbackGame {
+        state: u64,
+        advances: u32,
+        loads: u32,
+        failures: Vec<String>
+    }
+
+    fn callback_game(user_data: *mut c_void) -> MutexGuard<'static, CallbackGame> {
+        lock(unsafe { &*(user_data as *const Mutex<CallbackGame>) })
+    }
+
+    extern fn game_save(user_data: *mut c_void, handle: CSessionHandle, frame: CFrame) {
+        let state = callback_game(user_data).state;
+        let bytes = state.to_le_bytes();
+        let result = unsafe { ggrs_session_complete_save_with_state(handle, frame, bytes.as_ptr(), bytes.len(), state) };
+        if result != CResult::Ok {
+            callback_game(user_data).failures.push(format!("Save of frame {} failed with {:?}", frame, result));
+        }
+    }
+
+    extern fn game_load(user_data: *mut c_void, handle: CSessionHandle, frame: CFrame) {
+        let mut bytes = [0u8; 8];
+        let length = unsafe { ggrs_session_load_game_state(handle, frame, bytes.as_mut_ptr(), bytes.len()) };
+        let mut game = callback_game(user_data);
+        if length != bytes.len() {
+            game.failures.push(format!("Load of frame {} returned {} bytes", frame, length));
+        }
+        game.state = u64::from_le_bytes(bytes);
+        game.loads += 1;
+    }
+
+    extern fn game_advance(user_data: *mut c_void, _handle: CSessionHandle, inputs: *const CRequest, num_inputs: usize) {
+        let inputs = unsafe { std::slice::from_raw_parts(inputs, num_inputs) };
+        let mut game = callback_game(user_data);
+        if num_inputs != 2 {
+            game.failures.push(format!("Advance with {} inputs", num_inputs));
+        }
+        for (player, input) in inputs.iter().enumerate() {
+            if !matches!(input.request_type, CRequestType::SetInput) || input.player_handle != player || input.input != player as CInput + 10 {
+                game.failures.push(format!("Unexpected input {} for player {}", input.input, input.player_handle));
+            }
+            game.state += input.input as u64;
+        }
+        game.advances += 1;
+    }
+
+    #[test]
+    fn callbacks_drive_a_synctest_session() {
+        let _ctx = TestContext::new();
+        let handle = start_synctest(ggrs_builder_create(), 2);
+        let game = Mutex::new(CallbackGame::default());
+        let user_data = &game as *const Mutex<CallbackGame> as *mut c_void;
+        assert_eq!(ggrs_session_set_callbacks(handle, Some(game_save), Some(game_load), Some(game_advance), user_data), CResult::Ok);
+
+        // SyncTest compares the checksums of resimulated frames, so loads have to restore the saved state
+        for _ in 0..10 {
+            advance_synctest(handle, 2);
+            assert!(drain_requests(handle).is_empty());
+        }
+        {
+            let game = lock(&game);
+            assert!(game.failures.is_empty(), "{:?}", game.failures);
+            assert!(game.loads > 0);
+            assert!(game.advances > 10);
+        }
+
+        assert_eq!(ggrs_session_set_callbacks(handle, Some(game_save), None, Some(game_advance), user_data), CResult::InvalidRequest);
+        assert_eq!(ggrs_session_set_callbacks(handle, None, None, None, std::ptr::null_mut()), CResult::Ok);
+        let advances = lock(&game).advances;
+        assert_eq!(ggrs_session_add_local_input(handle, 0, 10), CResult::Ok);
+        assert_eq!(ggrs_session_add_local_input(handle, 1, 11), CResult::Ok);
+        assert_eq!(ggrs_session_advance_frame(handle), CResult::Ok);
+        let requests = drain_requests(handle);
+        assert!(requests.iter().any(|r| matches!(r.request_type, CRequestType::AdvanceFrame)));
+        assert_eq!(lock(&game).advances, advances);
+    }
+
+    #[cfg(feature = "c_socket")]
+    #[test]
+    fn disconnected_players_get_disconnected_inputs() {
+        let _ctx = TestContext::new();
+        let peers = start_pair(|_| {});
+        run_pair(&peers, 5, |_, frame| frame as CChecksum);
+
+        let handle = peers[0].handle;
+        assert_eq!(ggrs_session_disconnect_player(handle, 1), CResult::Ok);
+        let mut disconnected = false;
+        for _ in 0..10 {
+            assert_eq!(ggrs_session_add_local_input(handle, 0, 1), CResult::Ok);
+            assert_eq!(ggrs_session_advance_frame(handle), CResult::Ok);
+            for req in drain_requests(handle) {
+                match req.request_type {
+                    CRequestType::SaveGameState => assert_eq!(ggrs_session_complete_save(handle, req.frame, req.frame as CChecksum), CResult::Ok),
+                    CRequestType::SetInput if req.player_handle == 1 => disconnected = matches!(req.input_status, CInputStatus::Disconnected),
+                    _ => {}
+                }
+            }
+        }
+        assert!(disconnected);
+    }
+
+    #[cfg(feature = "c_socket")]
+    #[test]
+    fn disconnect_player_rejects_invalid_players() {
+        let _ctx = TestContext::new();
+        let peers = start_pair(|_| {});
+        assert_eq!(ggrs_session_disconnect_player(peers[0].handle, 0), CResult::InvalidRequest);
+        assert_eq!(ggrs_session_disconnect_player(peers[0].handle, 5), CResult::InvalidRequest);
+
+        let handle = start_synctest(ggrs_builder_create(), 2);
+        assert_eq!(ggrs_session_disconnect_player(handle, 1), CResult::InvalidRequest);
+    }
+
+    fn empty_stats() -> CNetworkStats {
+        CNetworkStats { send_queue_len: 0, ping: 0, kbps_sent: 0, local_frames_behind: 0, remote_frames_behind: 0 }
+    }
+
+    #[cfg(feature = "c_socket")]
+    #[test]
+    fn network_stats_of_remote_players() {
+        let _ctx = TestContext::new();
+        let peers = start_pair(|_| {});
+        run_pair(&peers, 5, |_, frame| frame as CChecksum);
+
+        // GGRS only reports stats once a full second has passed since synchronizing
+        let mut stats = empty_stats();
+        let mut result = CResult::NotSynchronized;
+        for _ in 0..300 {
+            result = ggrs_session_network_stats(peers[0].handle, 1, &mut stats);
+            if result != CResult::NotSynchronized {
+                break;
+            }
+            for peer in &peers {
+                assert_eq!(ggrs_session_poll_remote_clients(peer.handle), CResult::Ok);
+            }
+            exchange(&peers);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(result, CResult::Ok);
+        assert!(stats.ping < 1000);
+        assert!(stats.send_queue_len < 128);
+        assert!(stats.kbps_sent < 1024);
+        assert!(stats.local_frames_behind.abs() <= 5 && stats.remote_frames_behind.abs() <= 5);
+
+        assert_eq!(ggrs_session_network_stats(peers[0].handle, 0, &mut stats), CResult::InvalidRequest);
+    }
+
+    #[test]
+    fn synctest_sessions_have_no_network_stats() {
+        let _ctx = TestContext::new();
+        let handle = start_synctest(ggrs_builder_create(), 2);
+        let mut stats = empty_stats();
+        assert_eq!(ggrs_session_network_stats(handle, 1, &mut stats), CResult::InvalidRequest);
     }
 }
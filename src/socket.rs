@@ -1,10 +1,18 @@
-use crate::CSessionHandle;
+use crate::{CSessionHandle, context, lock};
+use crate::address::{Endpoint, INVALID_ADDRESS};
+use crate::error::{CResult, invalid_handle, report};
 use ggrs::Message;
 use rmp_serde;
 
 use std::collections::{BTreeMap, VecDeque};
+use std::ffi::{CStr, c_char, c_void};
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
 
 pub const CMESSAGE_BUFFER_SIZE: usize = 255;
+// Same as the receive buffer of the UdpNonBlockingSocket of GGRS
+const UDP_RECV_BUFFER_SIZE: usize = 4096;
 
 pub type CAddressHandle = u32;
 
@@ -15,74 +23,889 @@ pub struct CMessage {
     bytes_length: u32
 }
 
-pub(crate) static mut SOCKET_OUT: BTreeMap<CSessionHandle, VecDeque<(CAddressHandle, Message)>> = BTreeMap::new();
-pub(crate) static mut SOCKET_IN: BTreeMap<CSessionHandle, VecDeque<(CAddressHandle, Message)>> = BTreeMap::new();
+pub type CInvalidMessageCallback = Option<extern fn(user_data: *mut c_void, session_handle: CSessionHandle, addr: CAddressHandle, bytes: *const u8, bytes_length: usize)>;
+
+#[derive(Clone, Copy)]
+struct InvalidMessageCallback {
+    callback: extern fn(*mut c_void, CSessionHandle, CAddressHandle, *const u8, usize),
+    user_data: *mut c_void
+}
+// user_data is only handed back to the game, on the thread that passes in the message
+unsafe impl Send for InvalidMessageCallback {}
+
+// Location of one message in the arena filled by ggrs_socket_out_messages
+#[repr(C)]
+pub struct CMessageSpan {
+    addr: CAddressHandle,
+    offset: usize,
+    length: usize
+}
+
+pub(crate) struct SocketQueues {
+    // Serialized when GGRS sends them, so their length is known before they are handed out
+    socket_out: VecDeque<(CAddressHandle, Vec<u8>)>,
+    socket_in: VecDeque<(CAddressHandle, Message)>,
+    // Messages that could not be decoded per sender, these are dropped
+    invalid_messages: BTreeMap<CAddressHandle, u32>,
+    invalid_message_callback: Option<InvalidMessageCallback>,
+    // Set while the session is pumped over UDP by ggrs_socket_udp_pump
+    udp: Option<Arc<UdpSocket>>,
+    // Set by ggrs_builder_set_transport, socket_out is then flushed to it once the session is unlocked
+    transport: Option<CTransport>
+}
+
+// Shared between the CSocket owned by GGRS and the context, so the network thread is not blocked by a session advancing
+pub(crate) type SharedQueues = Arc<Mutex<SocketQueues>>;
+
+pub(crate) fn new_queues(transport: Option<CTransport>) -> SharedQueues {
+    Arc::new(Mutex::new(SocketQueues {
+        socket_out: VecDeque::new(),
+        socket_in: VecDeque::new(),
+        invalid_messages: BTreeMap::new(),
+        invalid_message_callback: None,
+        udp: None,
+        transport
+    }))
+}
+
+pub(crate) fn open(session_handle: CSessionHandle, queues: SharedQueues) {
+    lock(&context().sockets).insert(session_handle, queues);
+}
+
+pub(crate) fn close(session_handle: CSessionHandle) {
+    lock(&context().sockets).remove(&session_handle);
+}
+
+fn queues(session_handle: CSessionHandle) -> Option<SharedQueues> {
+    lock(&context().sockets).get(&session_handle).cloned()
+}
+
+// Lets the game's network layer send and receive directly instead of using ggrs_socket_out_message
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CTransport {
+    // Called with every serialized message GGRS sends, after the session is unlocked again
+    send: Option<extern fn(user_data: *mut c_void, addr: CAddressHandle, bytes: *const u8, bytes_length: usize)>,
+    // Optional, called before the session polls the received messages, while it is not locked yet
+    receive_all: Option<extern fn(user_data: *mut c_void, session_handle: CSessionHandle)>,
+    user_data: *mut c_void
+}
+// user_data is only handed back to the game, which has to make its transport thread-safe itself
+unsafe impl Send for CTransport {}
+impl CTransport {
+    pub(crate) fn validate(&self) -> CResult {
+        match self.send {
+            Some(_) => CResult::Ok,
+            None => report(CResult::InvalidRequest, "A transport needs a send callback.")
+        }
+    }
+}
+
+// Sends all messages queued for the transport of the session, without any lock held so send may call back into the library
+pub(crate) fn flush_transport(session_handle: CSessionHandle) {
+    let queues = match queues(session_handle) {
+        Some(q) => q,
+        None => return
+    };
+    let (send, user_data, outgoing) = {
+        let mut queues = lock(&queues);
+        match queues.transport {
+            Some(CTransport { send: Some(send), user_data, .. }) => (send, user_data, queues.socket_out.drain(..).collect::<Vec<_>>()),
+            _ => return
+        }
+    };
+    for (addr, buf) in outgoing {
+        send(user_data, addr, buf.as_ptr(), buf.len());
+    }
+}
+
+// Lets the transport of the session queue its received messages, without any lock held
+pub(crate) fn receive_transport(session_handle: CSessionHandle) {
+    let transport = match queues(session_handle) {
+        Some(q) => lock(&q).transport,
+        None => return
+    };
+    if let Some(CTransport { receive_all: Some(receive_all), user_data, .. }) = transport {
+        receive_all(user_data, session_handle);
+    }
+}
 
 pub struct CSocket {
-    session_handle: CSessionHandle,
+    queues: SharedQueues
 }
 impl CSocket {
-    pub const fn new(session_handle: CSessionHandle) -> Self {
+    pub(crate) const fn new(queues: SharedQueues) -> Self {
         Self {
-            session_handle
+            queues
         }
     }
 }
 
 impl ggrs::NonBlockingSocket<CAddressHandle> for CSocket {
     fn send_to(&mut self, msg: &Message, addr: &CAddressHandle){
-        unsafe {
-            let sock_out = SOCKET_OUT.get_mut(&self.session_handle).unwrap();
-            sock_out.push_back((*addr, msg.clone()));
-        }
+        let buf = match rmp_serde::to_vec(msg) {
+            Ok(b) => b,
+            Err(e) => {
+                report(CResult::InvalidMessage, format!("Unable to encode message: {}", e));
+                return;
+            }
+        };
+        lock(&self.queues).socket_out.push_back((*addr, buf));
     }
 
     fn receive_all_messages(&mut self) -> Vec<(CAddressHandle, Message)>{
         let mut result: Vec<(CAddressHandle, Message)> = Vec::new();
-        
-        unsafe {
-            let sock_in = SOCKET_IN.get_mut(&self.session_handle).unwrap();
-            while !sock_in.is_empty() {
-                match sock_in.pop_front() {
-                    Some(m) => {
-                        result.push((m.0, m.1));
-                    }
-                    None => {}
-                }
-            }
+        let mut queues = lock(&self.queues);
+        while let Some(m) = queues.socket_in.pop_front() {
+            result.push((m.0, m.1));
         }
         result
     }
 }
 
+fn in_message(session_handle: CSessionHandle, addr: CAddressHandle, bytes: &[u8]) -> CResult {
+    let queues = match queues(session_handle) {
+        Some(q) => q,
+        None => return invalid_handle(session_handle)
+    };
+
+    match rmp_serde::from_slice::<Message>(bytes) {
+        Ok(msg_ggrs) => {
+            lock(&queues).socket_in.push_back((addr, msg_ggrs));
+            CResult::Ok
+        }
+        Err(e) => {
+            invalid_message(session_handle, &queues, addr, bytes);
+            report(CResult::InvalidMessage, format!("Unable to decode message: {}", e))
+        }
+    }
+}
+
+// Counts a dropped message of addr and hands it to the invalid message callback
+fn invalid_message(session_handle: CSessionHandle, queues: &SharedQueues, addr: CAddressHandle, bytes: &[u8]) {
+    let callback = {
+        let mut queues = lock(queues);
+        *queues.invalid_messages.entry(addr).or_insert(0) += 1;
+        queues.invalid_message_callback
+    };
+    // Called without the queues locked, so the callback may query the counters
+    if let Some(c) = callback {
+        (c.callback)(c.user_data, session_handle, addr, bytes.as_ptr(), bytes.len());
+    }
+}
+
 #[no_mangle]
-pub extern fn ggrs_socket_in_message(session_handle: CSessionHandle, msg: &CMessage) {
-    unsafe {
-        let sock_in = SOCKET_IN.get_mut(&session_handle).unwrap();
-        let msg_ggrs: Message = rmp_serde::from_slice(&msg.bytes[0..msg.bytes_length as usize]).unwrap();
-        sock_in.push_back((msg.addr, msg_ggrs));
+pub extern fn ggrs_socket_in_message(session_handle: CSessionHandle, msg: &CMessage) -> CResult {
+    if msg.bytes_length as usize > CMESSAGE_BUFFER_SIZE {
+        return report(CResult::InvalidMessage, format!("Message length {} exceeds CMESSAGE_BUFFER_SIZE.", msg.bytes_length));
     }
+    in_message(session_handle, msg.addr, &msg.bytes[0..msg.bytes_length as usize])
 }
 
+/// # Safety
+/// `bytes` must point to at least `bytes_length` readable bytes.
+///
+/// Like ggrs_socket_in_message, for messages of any length.
+#[no_mangle]
+pub unsafe extern fn ggrs_socket_in_message_bytes(session_handle: CSessionHandle, addr: CAddressHandle, bytes: *const u8, bytes_length: usize) -> CResult {
+    if bytes.is_null() {
+        return report(CResult::InvalidMessage, "Message bytes are null.");
+    }
+    in_message(session_handle, addr, unsafe { std::slice::from_raw_parts(bytes, bytes_length) })
+}
+
+// Pops the next message if it fits in buffer_length and returns its length, too large messages stay queued unless drop_too_large is set
+fn out_message(session_handle: CSessionHandle, buffer_length: usize, drop_too_large: bool, f: impl FnOnce(CAddressHandle, &[u8])) -> Result<usize, CResult> {
+    let queues = match queues(session_handle) {
+        Some(q) => q,
+        None => return Err(invalid_handle(session_handle))
+    };
+    let mut queues = lock(&queues);
+
+    let length = match queues.socket_out.front() {
+        Some((_, buf)) => buf.len(),
+        None => return Ok(0)
+    };
+    if length > buffer_length {
+        if drop_too_large {
+            queues.socket_out.pop_front();
+            report(CResult::BufferTooSmall, format!("Dropped message of {} bytes, it does not fit in a buffer of {} bytes.", length, buffer_length));
+        } else {
+            report(CResult::BufferTooSmall, format!("Message of {} bytes does not fit in a buffer of {} bytes.", length, buffer_length));
+        }
+        return Ok(length);
+    }
+    if let Some((addr, buf)) = queues.socket_out.pop_front() {
+        f(addr, &buf);
+    }
+    Ok(length)
+}
+
+/// Returns false when no message is queued. Messages larger than CMESSAGE_BUFFER_SIZE are dropped and reported as BufferTooSmall,
+/// so they never hold up the messages behind them. Use ggrs_socket_out_message_bytes when messages may be larger.
 #[no_mangle]
 pub extern fn ggrs_socket_out_message(session_handle: CSessionHandle, msg: &mut CMessage) -> bool {
-    unsafe {
-        let sock_out = SOCKET_OUT.get_mut(&session_handle).unwrap();
-        match sock_out.pop_front() {
-            Some(m) => {
-                let buf = rmp_serde::to_vec(&m.1).unwrap();
-
-                msg.addr = m.0;
-                msg.bytes_length = 0;
-                for byte in buf {
-                    if msg.bytes_length < CMESSAGE_BUFFER_SIZE as u32 {
-                        msg.bytes[msg.bytes_length as usize] = byte;
-                    }
-                    msg.bytes_length += 1;
+    loop {
+        let result = out_message(session_handle, CMESSAGE_BUFFER_SIZE, true, |addr, buf| {
+            msg.addr = addr;
+            msg.bytes[..buf.len()].copy_from_slice(buf);
+            msg.bytes_length = buf.len() as u32;
+        });
+        match result {
+            Ok(length) if length > CMESSAGE_BUFFER_SIZE => continue,
+            Ok(length) => return length > 0,
+            Err(_) => return false
+        }
+    }
+}
+
+/// # Safety
+/// `buffer` must be null or point to at least `buffer_length` writable bytes.
+///
+/// Returns the length of the next queued message, or 0 when none is queued.
+/// The message is only copied and removed from the queue if buffer_length is large enough, otherwise BufferTooSmall is reported.
+#[no_mangle]
+pub unsafe extern fn ggrs_socket_out_message_bytes(session_handle: CSessionHandle, addr: &mut CAddressHandle, buffer: *mut u8, buffer_length: usize) -> usize {
+    let buffer_length = if buffer.is_null() { 0 } else { buffer_length };
+    let result = out_message(session_handle, buffer_length, false, |a, buf| {
+        *addr = a;
+        unsafe { std::ptr::copy_nonoverlapping(buf.as_ptr(), buffer, buf.len()) };
+    });
+    result.unwrap_or(0)
+}
+
+/// # Safety
+/// `arena` must point to at least `arena_length` writable bytes and `spans` to at least `max_spans` writable spans.
+///
+/// Moves as many queued messages as fit into arena, back to back, and returns how many were written.
+/// The span of each message gives its address and location in arena, more is set when messages remain queued.
+/// BufferTooSmall is reported when the next message does not fit in an empty arena.
+#[no_mangle]
+pub unsafe extern fn ggrs_socket_out_messages(session_handle: CSessionHandle, arena: *mut u8, arena_length: usize, spans: *mut CMessageSpan, max_spans: usize, more: &mut bool) -> usize {
+    let queues = match queues(session_handle) {
+        Some(q) => q,
+        None => {
+            *more = false;
+            invalid_handle(session_handle);
+            return 0;
+        }
+    };
+    let mut queues = lock(&queues);
+    let mut num_messages: usize = 0;
+    let mut offset: usize = 0;
+
+    while num_messages < max_spans && !arena.is_null() && !spans.is_null() {
+        let length = match queues.socket_out.front() {
+            Some((_, buf)) => buf.len(),
+            None => break
+        };
+        if offset + length > arena_length {
+            if num_messages == 0 {
+                report(CResult::BufferTooSmall, format!("Message of {} bytes does not fit in an arena of {} bytes.", length, arena_length));
+            }
+            break;
+        }
+
+        if let Some((addr, buf)) = queues.socket_out.pop_front() {
+            unsafe {
+                std::ptr::copy_nonoverlapping(buf.as_ptr(), arena.add(offset), length);
+                spans.add(num_messages).write(CMessageSpan { addr, offset, length });
+            }
+            offset += length;
+            num_messages += 1;
+        }
+    }
+
+    *more = !queues.socket_out.is_empty();
+    num_messages
+}
+
+/// Returns how many messages from addr were dropped because they could not be decoded.
+#[no_mangle]
+pub extern fn ggrs_socket_invalid_message_count(session_handle: CSessionHandle, addr: CAddressHandle) -> u32 {
+    match queues(session_handle) {
+        Some(queues) => lock(&queues).invalid_messages.get(&addr).copied().unwrap_or(0),
+        None => {
+            invalid_handle(session_handle);
+            0
+        }
+    }
+}
+
+/// Returns how many messages were dropped because they could not be decoded, from any address.
+#[no_mangle]
+pub extern fn ggrs_socket_invalid_message_total(session_handle: CSessionHandle) -> u32 {
+    match queues(session_handle) {
+        Some(queues) => lock(&queues).invalid_messages.values().sum(),
+        None => {
+            invalid_handle(session_handle);
+            0
+        }
+    }
+}
+
+#[no_mangle]
+pub extern fn ggrs_socket_reset_invalid_message_counts(session_handle: CSessionHandle) -> CResult {
+    match queues(session_handle) {
+        Some(queues) => {
+            lock(&queues).invalid_messages.clear();
+            CResult::Ok
+        }
+        None => invalid_handle(session_handle)
+    }
+}
+
+/// Calls callback with every message that is dropped because it could not be decoded, null removes the callback.
+#[no_mangle]
+pub extern fn ggrs_socket_set_invalid_message_callback(session_handle: CSessionHandle, callback: CInvalidMessageCallback, user_data: *mut c_void) -> CResult {
+    match queues(session_handle) {
+        Some(queues) => {
+            lock(&queues).invalid_message_callback = callback.map(|callback| InvalidMessageCallback { callback, user_data });
+            CResult::Ok
+        }
+        None => invalid_handle(session_handle)
+    }
+}
+
+/// # Safety
+/// `bind_addr` must be null or a valid nul-terminated string.
+///
+/// Binds a non-blocking UDP socket on the "ip:port" bind_addr for the session, from then on ggrs_socket_udp_pump moves its messages.
+/// "0.0.0.0:port" only reaches IPv4 peers, "[::]:port" reaches IPv6 peers and also IPv4 peers where IPv6 sockets are dual-stack.
+/// Remote players and spectators have to be added with address handles from ggrs_address_register.
+/// Fails with InvalidRequest for sessions built with a transport, which already move their own messages.
+#[no_mangle]
+pub unsafe extern fn ggrs_socket_udp_bind(session_handle: CSessionHandle, bind_addr: *const c_char) -> CResult {
+    let queues = match queues(session_handle) {
+        Some(q) => q,
+        None => return invalid_handle(session_handle)
+    };
+    if lock(&queues).transport.is_some() {
+        return report(CResult::InvalidRequest, format!("Session {} sends through its transport and can not bind a UDP socket.", session_handle));
+    }
+    let bind_addr = if bind_addr.is_null() {
+        None
+    } else {
+        unsafe { CStr::from_ptr(bind_addr) }.to_str().ok().and_then(|s| s.parse::<SocketAddr>().ok())
+    };
+    let bind_addr = match bind_addr {
+        Some(a) => a,
+        None => return report(CResult::InvalidAddress, "Unable to parse bind address, expected ip:port.")
+    };
+
+    let socket = match UdpSocket::bind(bind_addr).and_then(|s| s.set_nonblocking(true).map(|_| s)) {
+        Ok(s) => s,
+        Err(e) => return report(CResult::SocketError, format!("Unable to bind {}: {}", bind_addr, e))
+    };
+    lock(&queues).udp = Some(Arc::new(socket));
+    CResult::Ok
+}
+
+/// Closes the UDP socket of the session, its messages are queued for ggrs_socket_out_message again.
+#[no_mangle]
+pub extern fn ggrs_socket_udp_close(session_handle: CSessionHandle) -> CResult {
+    match queues(session_handle) {
+        Some(queues) => {
+            lock(&queues).udp = None;
+            CResult::Ok
+        }
+        None => invalid_handle(session_handle)
+    }
+}
+
+/// Sends all queued messages of the session over its UDP socket and queues all received datagrams.
+/// Datagrams that can not be decoded are counted and dropped, as are datagrams from senders without an address handle,
+/// which are counted for INVALID_ADDRESS.
+#[no_mangle]
+pub extern fn ggrs_socket_udp_pump(session_handle: CSessionHandle) -> CResult {
+    let queues = match queues(session_handle) {
+        Some(q) => q,
+        None => return invalid_handle(session_handle)
+    };
+    let (socket, outgoing) = {
+        let mut queues = lock(&queues);
+        match queues.udp.clone() {
+            Some(socket) => (socket, queues.socket_out.drain(..).collect::<Vec<_>>()),
+            None => return report(CResult::InvalidRequest, format!("Session {} has no UDP socket, bind one with ggrs_socket_udp_bind.", session_handle))
+        }
+    };
+    let mut result = CResult::Ok;
+    let ipv6 = socket.local_addr().map(|a| a.is_ipv6()).unwrap_or(false);
+
+    // Send, messages to unknown or non UDP endpoints are dropped
+    for (addr, buf) in outgoing {
+        let endpoint = lock(&context().addresses).endpoint(addr).cloned();
+        match endpoint {
+            Some(Endpoint::Udp(socket_addr)) => {
+                if let Err(e) = socket.send_to(&buf, to_socket_addr(socket_addr, ipv6)) {
+                    result = report(CResult::SocketError, format!("Unable to send to {}: {}", socket_addr, e));
                 }
-                true
-            },
-            None => false
+            }
+            _ => result = report(CResult::InvalidAddress, format!("Address handle {} is not a registered ip:port endpoint.", addr))
         }
     }
+
+    // Receive until the socket would block
+    let mut buf = [0u8; UDP_RECV_BUFFER_SIZE];
+    loop {
+        match socket.recv_from(&mut buf) {
+            Ok((length, socket_addr)) => {
+                // Only known peers are looked up, registering every sender would let anyone grow the registry
+                let addr = lock(&context().addresses).handle(&Endpoint::Udp(from_socket_addr(socket_addr)));
+                match addr {
+                    // Malformed datagrams are already counted, they do not fail the pump
+                    Some(addr) => { in_message(session_handle, addr, &buf[..length]); }
+                    None => invalid_message(session_handle, &queues, INVALID_ADDRESS, &buf[..length])
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            // Windows reports ICMP port unreachable of earlier sends here
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => continue,
+            Err(e) => {
+                result = report(CResult::SocketError, format!("Unable to receive: {}", e));
+                break;
+            }
+        }
+    }
+
+    result
+}
+
+// Dual-stack sockets address IPv4 peers with IPv4-mapped IPv6 addresses, the registry keeps them as IPv4
+fn to_socket_addr(addr: SocketAddr, ipv6: bool) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) if ipv6 => SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+        _ => addr
+    }
+}
+
+fn from_socket_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+            None => addr
+        },
+        _ => addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use crate::error::ggrs_last_error_code;
+    use crate::tests::TestContext;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    // One end of an in-memory link between two sessions built with a transport
+    struct Link {
+        session: AtomicU32,
+        peer: AtomicU32,
+        addr: CAddressHandle,
+        sent: AtomicU32,
+        received: AtomicU32,
+        failures: AtomicU32
+    }
+
+    // Both callbacks call back into the library, which needs the session to be unlocked
+    extern fn link_send(user_data: *mut c_void, _addr: CAddressHandle, bytes: *const u8, bytes_length: usize) {
+        let link = unsafe { &*(user_data as *const Link) };
+        ggrs_session_current_state(link.session.load(Ordering::Relaxed));
+        let result = unsafe { ggrs_socket_in_message_bytes(link.peer.load(Ordering::Relaxed), link.addr, bytes, bytes_length) };
+        if result != CResult::Ok {
+            link.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        link.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    extern fn link_receive_all(user_data: *mut c_void, session_handle: CSessionHandle) {
+        let link = unsafe { &*(user_data as *const Link) };
+        if ggrs_session_current_frame(session_handle) == NULL_FRAME {
+            link.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        link.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn transport_callbacks_may_call_back_into_the_library() {
+        let _ctx = TestContext::new();
+        let links = [1, 2].map(|addr| Link {
+            session: AtomicU32::new(INVALID_HANDLE),
+            peer: AtomicU32::new(INVALID_HANDLE),
+            addr,
+            sent: AtomicU32::new(0),
+            received: AtomicU32::new(0),
+            failures: AtomicU32::new(0)
+        });
+        let handles = [0, 1].map(|player| {
+            let builder = ggrs_builder_create();
+            let transport = CTransport {
+                send: Some(link_send),
+                receive_all: Some(link_receive_all),
+                user_data: &links[player] as *const Link as *mut c_void
+            };
+            assert_eq!(ggrs_builder_set_transport_ex(builder, &transport), CResult::Ok);
+            assert_eq!(ggrs_builder_add_local_player_ex(builder, player), CResult::Ok);
+            assert_eq!(ggrs_builder_add_remote_player_ex(builder, 1 - player, links[1 - player].addr), CResult::Ok);
+            let handle = ggrs_builder_start_p2p_session_ex(builder);
+            assert_ne!(handle, INVALID_HANDLE);
+            handle
+        });
+        for player in 0..2 {
+            links[player].session.store(handles[player], Ordering::Relaxed);
+            links[player].peer.store(handles[1 - player], Ordering::Relaxed);
+        }
+
+        for _ in 0..2000 {
+            if handles.iter().all(|h| matches!(ggrs_session_current_state(*h), CSessionState::Running)) {
+                break;
+            }
+            for handle in handles {
+                assert_eq!(ggrs_session_poll_remote_clients(handle), CResult::Ok);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert!(handles.iter().all(|h| matches!(ggrs_session_current_state(*h), CSessionState::Running)));
+        for link in &links {
+            assert_eq!(link.failures.load(Ordering::Relaxed), 0);
+            assert!(link.sent.load(Ordering::Relaxed) > 0 && link.received.load(Ordering::Relaxed) > 0);
+        }
+        // Nothing is left queued for ggrs_socket_out_message
+        assert!(handles.iter().all(|h| lock(&queues(*h).unwrap()).socket_out.is_empty()));
+    }
+
+    // A session whose queues only hold what the test puts in them
+    fn idle_session() -> CSessionHandle {
+        let handle = crate::tests::start_pair(|_| {})[0].handle;
+        let queues = queues(handle).unwrap();
+        let mut queues = lock(&queues);
+        queues.socket_out.clear();
+        queues.socket_in.clear();
+        handle
+    }
+
+    fn queue_out(session_handle: CSessionHandle, addr: CAddressHandle, bytes: Vec<u8>) {
+        lock(&queues(session_handle).unwrap()).socket_out.push_back((addr, bytes));
+    }
+
+    fn empty_message() -> CMessage {
+        CMessage { addr: INVALID_ADDRESS, bytes: [0; CMESSAGE_BUFFER_SIZE], bytes_length: 0 }
+    }
+
+    #[test]
+    fn oversized_messages_wait_for_a_large_enough_buffer() {
+        let _ctx = TestContext::new();
+        let handle = idle_session();
+        queue_out(handle, 3, vec![7; CMESSAGE_BUFFER_SIZE + 100]);
+
+        let mut addr = INVALID_ADDRESS;
+        let mut buffer = vec![0u8; CMESSAGE_BUFFER_SIZE + 100];
+        assert_eq!(unsafe { ggrs_socket_out_message_bytes(handle, &mut addr, buffer.as_mut_ptr(), CMESSAGE_BUFFER_SIZE) }, buffer.len());
+        assert_eq!(ggrs_last_error_code(), CResult::BufferTooSmall);
+        assert_eq!(addr, INVALID_ADDRESS);
+
+        assert_eq!(unsafe { ggrs_socket_out_message_bytes(handle, &mut addr, buffer.as_mut_ptr(), buffer.len()) }, buffer.len());
+        assert_eq!(addr, 3);
+        assert!(buffer.iter().all(|b| *b == 7));
+        assert_eq!(unsafe { ggrs_socket_out_message_bytes(handle, &mut addr, buffer.as_mut_ptr(), buffer.len()) }, 0);
+    }
+
+    #[test]
+    fn oversized_messages_do_not_stall_out_message() {
+        let _ctx = TestContext::new();
+        let handle = idle_session();
+        queue_out(handle, 1, vec![1; 10]);
+        queue_out(handle, 2, vec![2; CMESSAGE_BUFFER_SIZE + 1]);
+        queue_out(handle, 3, vec![3; CMESSAGE_BUFFER_SIZE]);
+
+        let mut msg = empty_message();
+        assert!(ggrs_socket_out_message(handle, &mut msg));
+        assert_eq!((msg.addr, msg.bytes_length), (1, 10));
+        // The message of 2 is dropped and reported
+        assert!(ggrs_socket_out_message(handle, &mut msg));
+        assert_eq!(ggrs_last_error_code(), CResult::BufferTooSmall);
+        assert_eq!((msg.addr, msg.bytes_length), (3, CMESSAGE_BUFFER_SIZE as u32));
+        assert!(!ggrs_socket_out_message(handle, &mut msg));
+    }
+
+    // Moves all messages of from to to and returns the length of the longest one
+    fn exchange_bytes(from: CSessionHandle, from_addr: CAddressHandle, to: CSessionHandle) -> usize {
+        let mut longest = 0;
+        let mut addr = INVALID_ADDRESS;
+        let mut buffer = [0u8; 4096];
+        loop {
+            let length = unsafe { ggrs_socket_out_message_bytes(from, &mut addr, buffer.as_mut_ptr(), buffer.len()) };
+            if length == 0 {
+                return longest;
+            }
+            assert_eq!(unsafe { ggrs_socket_in_message_bytes(to, from_addr, buffer.as_ptr(), length) }, CResult::Ok);
+            longest = longest.max(length);
+        }
+    }
+
+    fn player_input(player: CPlayerHandle, frame: usize) -> [u8; CINPUT_BUFFER_SIZE] {
+        std::array::from_fn(|i| (i * 31 + player * 17 + frame * 7) as u8)
+    }
+
+    #[test]
+    fn oversized_messages_are_passed_as_bytes() {
+        let _ctx = TestContext::new();
+        let (addr_a, addr_b) = (1, 2);
+        // Four local players with full size inputs on a, so its input messages outgrow CMessage
+        let start = |locals: std::ops::Range<usize>, remote_addr: CAddressHandle| {
+            let builder = ggrs_builder_create();
+            assert_eq!(ggrs_builder_with_num_players_ex(builder, 5), CResult::Ok);
+            assert_eq!(ggrs_builder_with_input_size_ex(builder, CINPUT_BUFFER_SIZE), CResult::Ok);
+            assert_eq!(ggrs_builder_with_input_delay_ex(builder, 0), CResult::Ok);
+            for player in 0..5 {
+                let result = if locals.contains(&player) {
+                    ggrs_builder_add_local_player_ex(builder, player)
+                } else {
+                    ggrs_builder_add_remote_player_ex(builder, player, remote_addr)
+                };
+                assert_eq!(result, CResult::Ok);
+            }
+            ggrs_builder_start_p2p_session_ex(builder)
+        };
+        let a = start(0..4, addr_b);
+        let b = start(4..5, addr_a);
+
+        for _ in 0..2000 {
+            if [a, b].iter().all(|h| matches!(ggrs_session_current_state(*h), CSessionState::Running)) {
+                break;
+            }
+            ggrs_session_poll_remote_clients(a);
+            ggrs_session_poll_remote_clients(b);
+            exchange_bytes(a, addr_a, b);
+            exchange_bytes(b, addr_b, a);
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        // Unacknowledged inputs are resent with every message
+        for frame in 0..2 {
+            for player in 0..4 {
+                let input = player_input(player, frame);
+                assert_eq!(unsafe { ggrs_session_add_local_input_bytes(a, player, input.as_ptr(), input.len()) }, CResult::Ok);
+            }
+            assert_eq!(ggrs_session_advance_frame(a), CResult::Ok);
+            crate::tests::drain_requests(a);
+        }
+        assert!(exchange_bytes(a, addr_a, b) > CMESSAGE_BUFFER_SIZE);
+
+        assert_eq!(ggrs_session_poll_remote_clients(b), CResult::Ok);
+        assert_eq!(ggrs_session_add_local_input(b, 4, 0), CResult::Ok);
+        assert_eq!(ggrs_session_advance_frame(b), CResult::Ok);
+        let inputs: Vec<CRequest> = crate::tests::drain_requests(b).into_iter().filter(|r| matches!(r.request_type, CRequestType::SetInput)).collect();
+        assert_eq!(inputs.len(), 5);
+        for (player, input) in inputs.iter().enumerate().take(4) {
+            assert!(matches!(input.input_status, CInputStatus::Confirmed));
+            assert_eq!(input.input_bytes, player_input(player, 0));
+        }
+    }
+
+    // Every dropped message with the counter of its address as seen from within the callback
+    type Dropped = Mutex<Vec<(CAddressHandle, Vec<u8>, u32)>>;
+
+    extern fn record_invalid(user_data: *mut c_void, session_handle: CSessionHandle, addr: CAddressHandle, bytes: *const u8, bytes_length: usize) {
+        let dropped = unsafe { &*(user_data as *const Dropped) };
+        let bytes = unsafe { std::slice::from_raw_parts(bytes, bytes_length) }.to_vec();
+        let count = ggrs_socket_invalid_message_count(session_handle, addr);
+        lock(dropped).push((addr, bytes, count));
+    }
+
+    #[test]
+    fn invalid_messages_are_counted_per_address() {
+        let _ctx = TestContext::new();
+        let handle = idle_session();
+        let dropped: Dropped = Mutex::new(Vec::new());
+        let user_data = &dropped as *const _ as *mut c_void;
+        assert_eq!(ggrs_socket_set_invalid_message_callback(handle, Some(record_invalid), user_data), CResult::Ok);
+
+        let garbage = [0xc1u8, 0xff, 0x00];
+        for addr in [5, 5, 6] {
+            assert_eq!(unsafe { ggrs_socket_in_message_bytes(handle, addr, garbage.as_ptr(), garbage.len()) }, CResult::InvalidMessage);
+        }
+        assert_eq!(ggrs_socket_invalid_message_count(handle, 5), 2);
+        assert_eq!(ggrs_socket_invalid_message_count(handle, 6), 1);
+        assert_eq!(ggrs_socket_invalid_message_count(handle, 7), 0);
+        assert_eq!(ggrs_socket_invalid_message_total(handle), 3);
+        assert!(lock(&queues(handle).unwrap()).socket_in.is_empty());
+
+        let dropped = lock(&dropped).clone();
+        assert_eq!(dropped.iter().map(|(addr, _, count)| (*addr, *count)).collect::<Vec<_>>(), [(5, 1), (5, 2), (6, 1)]);
+        assert!(dropped.iter().all(|(_, bytes, _)| bytes == &garbage));
+
+        assert_eq!(ggrs_socket_reset_invalid_message_counts(handle), CResult::Ok);
+        assert_eq!(ggrs_socket_invalid_message_total(handle), 0);
+        assert_eq!(ggrs_socket_set_invalid_message_callback(handle, None, std::ptr::null_mut()), CResult::Ok);
+        assert_eq!(unsafe { ggrs_socket_in_message_bytes(handle, 5, garbage.as_ptr(), garbage.len()) }, CResult::InvalidMessage);
+        assert_eq!(ggrs_socket_invalid_message_count(handle, 5), 1);
+    }
+
+    #[test]
+    fn invalid_message_counters_need_a_session() {
+        let _ctx = TestContext::new();
+        assert_eq!(ggrs_socket_invalid_message_total(INVALID_HANDLE), 0);
+        assert_eq!(ggrs_last_error_code(), CResult::InvalidHandle);
+        assert_eq!(ggrs_socket_reset_invalid_message_counts(INVALID_HANDLE), CResult::InvalidHandle);
+    }
+
+    fn out_messages(session_handle: CSessionHandle, arena: &mut [u8], max_spans: usize) -> (Vec<CMessageSpan>, bool) {
+        let mut spans: Vec<CMessageSpan> = (0..max_spans).map(|_| CMessageSpan { addr: INVALID_ADDRESS, offset: 0, length: 0 }).collect();
+        let mut more = false;
+        let num_messages = unsafe { ggrs_socket_out_messages(session_handle, arena.as_mut_ptr(), arena.len(), spans.as_mut_ptr(), max_spans, &mut more) };
+        spans.truncate(num_messages);
+        (spans, more)
+    }
+
+    #[test]
+    fn out_messages_fills_the_arena_back_to_back() {
+        let _ctx = TestContext::new();
+        let handle = idle_session();
+        for (addr, length) in [(1, 10), (2, 20), (3, 30)] {
+            queue_out(handle, addr, vec![addr as u8; length]);
+        }
+
+        let mut arena = [0u8; 40];
+        let (spans, more) = out_messages(handle, &mut arena, 8);
+        assert!(more);
+        assert_eq!(spans.iter().map(|s| (s.addr, s.offset, s.length)).collect::<Vec<_>>(), [(1, 0, 10), (2, 10, 20)]);
+        assert!(arena[..10].iter().all(|b| *b == 1) && arena[10..30].iter().all(|b| *b == 2));
+
+        let (spans, more) = out_messages(handle, &mut arena, 8);
+        assert!(!more);
+        assert_eq!(spans.iter().map(|s| (s.addr, s.offset, s.length)).collect::<Vec<_>>(), [(3, 0, 30)]);
+
+        let (spans, more) = out_messages(handle, &mut arena, 8);
+        assert!(spans.is_empty() && !more);
+    }
+
+    #[test]
+    fn out_messages_stops_at_max_spans() {
+        let _ctx = TestContext::new();
+        let handle = idle_session();
+        queue_out(handle, 1, vec![1; 4]);
+        queue_out(handle, 2, vec![2; 4]);
+
+        let mut arena = [0u8; 64];
+        let (spans, more) = out_messages(handle, &mut arena, 1);
+        assert_eq!(spans.len(), 1);
+        assert!(more);
+    }
+
+    #[test]
+    fn out_messages_reports_a_too_small_arena() {
+        let _ctx = TestContext::new();
+        let handle = idle_session();
+        queue_out(handle, 1, vec![1; 100]);
+
+        let mut arena = [0u8; 64];
+        let (spans, more) = out_messages(handle, &mut arena, 8);
+        assert!(spans.is_empty() && more);
+        assert_eq!(ggrs_last_error_code(), CResult::BufferTooSmall);
+
+        let mut arena = [0u8; 100];
+        let (spans, more) = out_messages(handle, &mut arena, 8);
+        assert_eq!(spans.len(), 1);
+        assert!(!more);
+    }
+
+    fn udp_bind(session_handle: CSessionHandle, bind_addr: &str) -> CResult {
+        let bind_addr = std::ffi::CString::new(bind_addr).unwrap();
+        unsafe { ggrs_socket_udp_bind(session_handle, bind_addr.as_ptr()) }
+    }
+
+    fn udp_local_addr(session_handle: CSessionHandle) -> SocketAddr {
+        lock(&queues(session_handle).unwrap()).udp.as_ref().unwrap().local_addr().unwrap()
+    }
+
+    #[test]
+    fn udp_pump_connects_two_sessions() {
+        let _ctx = TestContext::new();
+        let ports = [0, 1].map(|_| UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port());
+        let addrs = ports.map(|port| lock(&context().addresses).register(Endpoint::Udp(SocketAddr::from(([127, 0, 0, 1], port)))));
+        let handles = [0, 1].map(|player| {
+            let builder = ggrs_builder_create();
+            assert_eq!(ggrs_builder_add_local_player_ex(builder, player), CResult::Ok);
+            assert_eq!(ggrs_builder_add_remote_player_ex(builder, 1 - player, addrs[1 - player]), CResult::Ok);
+            let handle = ggrs_builder_start_p2p_session_ex(builder);
+            assert_eq!(udp_bind(handle, &format!("127.0.0.1:{}", ports[player])), CResult::Ok);
+            handle
+        });
+
+        for _ in 0..2000 {
+            if handles.iter().all(|h| matches!(ggrs_session_current_state(*h), CSessionState::Running)) {
+                break;
+            }
+            for handle in handles {
+                assert_eq!(ggrs_session_poll_remote_clients(handle), CResult::Ok);
+                assert_eq!(ggrs_socket_udp_pump(handle), CResult::Ok);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert!(handles.iter().all(|h| matches!(ggrs_session_current_state(*h), CSessionState::Running)));
+        assert!(handles.iter().all(|h| ggrs_socket_invalid_message_total(*h) == 0));
+
+        assert_eq!(ggrs_socket_udp_close(handles[0]), CResult::Ok);
+        assert_eq!(ggrs_socket_udp_pump(handles[0]), CResult::InvalidRequest);
+    }
+
+    #[test]
+    fn udp_pump_drops_datagrams_from_unknown_senders() {
+        let _ctx = TestContext::new();
+        let handle = idle_session();
+        assert_eq!(udp_bind(handle, "127.0.0.1:0"), CResult::Ok);
+        let target = udp_local_addr(handle);
+
+        let unknown = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let known = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let known_addr = lock(&context().addresses).register(Endpoint::Udp(known.local_addr().unwrap()));
+        for _ in 0..2 {
+            unknown.send_to(&[0xc1], target).unwrap();
+        }
+        known.send_to(&[0xc1], target).unwrap();
+
+        for _ in 0..1000 {
+            assert_eq!(ggrs_socket_udp_pump(handle), CResult::Ok);
+            if ggrs_socket_invalid_message_total(handle) == 3 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert_eq!(ggrs_socket_invalid_message_count(handle, INVALID_ADDRESS), 2);
+        assert_eq!(ggrs_socket_invalid_message_count(handle, known_addr), 1);
+        assert_eq!(lock(&context().addresses).handle(&Endpoint::Udp(unknown.local_addr().unwrap())), None);
+    }
+
+    #[test]
+    fn udp_bind_checks_its_session() {
+        let _ctx = TestContext::new();
+        let handle = idle_session();
+        assert_eq!(udp_bind(INVALID_HANDLE, "127.0.0.1:0"), CResult::InvalidHandle);
+        assert_eq!(udp_bind(handle, "127.0.0.1"), CResult::InvalidAddress);
+        assert_eq!(unsafe { ggrs_socket_udp_bind(handle, std::ptr::null()) }, CResult::InvalidAddress);
+        assert_eq!(ggrs_socket_udp_pump(handle), CResult::InvalidRequest);
+
+        let builder = ggrs_builder_create();
+        let transport = CTransport { send: Some(link_send), receive_all: None, user_data: std::ptr::null_mut() };
+        assert_eq!(ggrs_builder_set_transport_ex(builder, &transport), CResult::Ok);
+        assert_eq!(ggrs_builder_add_local_player_ex(builder, 0), CResult::Ok);
+        assert_eq!(ggrs_builder_add_remote_player_ex(builder, 1, 1), CResult::Ok);
+        let with_transport = ggrs_builder_start_p2p_session_ex(builder);
+        assert_eq!(udp_bind(with_transport, "127.0.0.1:0"), CResult::InvalidRequest);
+    }
+
+    #[test]
+    fn dual_stack_sockets_map_ipv4_addresses() {
+        let v4: SocketAddr = "10.0.0.1:7000".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:10.0.0.1]:7000".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:7000".parse().unwrap();
+
+        assert_eq!(to_socket_addr(v4, true), mapped);
+        assert_eq!(to_socket_addr(v4, false), v4);
+        assert_eq!(to_socket_addr(v6, true), v6);
+        assert_eq!(from_socket_addr(mapped), v4);
+        assert_eq!(from_socket_addr(v6), v6);
+        assert_eq!(from_socket_addr(v4), v4);
+    }
 }